@@ -1,132 +1,348 @@
-mod readme;
-mod zip;
+pub mod readme;
+mod update;
+pub mod zip;
 use self::zip::ZipWriter;
-use kin_core::{bail, fsutil, info, libsodium};
-use kin_core::{BackupPackage, CompileArgs, EncryptedMasterKey, Error, KinProject, KinSettings};
+use kin_core::libsodium::{Encryptor, KdfParams, SigningKey};
+use kin_core::{bail, fsutil, info, validation};
+use kin_core::{ArchiveBinding, BackupPackage, CompileArgs, Error, IssuedPackage};
+use kin_core::{KinProject, KinSettings, Manifest, ProjectFile};
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
 use std::iter::Iterator;
 use std::path::PathBuf;
 
+/// The decrypt program for each platform, zipped up by
+/// scripts/prepare-decrypt-exes.sh
+pub const DECRYPT_EXECUTABLES: &[u8] = include_bytes!("decrypt_executables.zip");
+
 pub fn run(args: &CompileArgs) -> Result<(), Error> {
     let project = match &args.project_dir {
         Some(dir) => KinProject::from(&dir),
         None => KinProject::from(&std::env::current_dir()?),
     };
 
-    let recip_name = &args.recipient;
-    let settings = match project.settings() {
+    if args.dry_run {
+        return print_archive_contents(&project);
+    }
+
+    let dest_dir = match &args.dest_dir {
+        Some(dir) => dir,
+        None => bail!("specify the directory to compile the backup into"),
+    };
+
+    let mut settings = match project.settings() {
         Ok(settings) => settings,
         Err(e) => bail!("unable to read settings: {}", e),
     };
 
-    let peers = settings.get_peers(recip_name)?;
-    let master_key = match settings.master_key() {
-        Ok(key) => key,
-        Err(e) => bail!("invalid master key: {}", e),
+    let signing_key = match settings.signing_key()? {
+        Some(key) => key,
+        None => {
+            info!("generating an owner signing key for this project");
+            settings.generate_signing_key();
+            project.write_settings(&settings)?;
+            settings.signing_key()?.unwrap()
+        }
     };
 
-    let encrypted_keys: Vec<EncryptedMasterKey> = peers
-        .iter()
-        .map(|x| master_key.encrypt(&x.passphrase).unwrap())
-        .collect();
+    let kdf_profile = match args.kdf_profile {
+        Some(profile) => profile,
+        None => settings.kdf_profile,
+    };
 
-    let dest_package = BackupPackage::init(&args.dest_dir, encrypted_keys)?;
+    let compiler = Compiler {
+        project: &project,
+        settings: &settings,
+        signing_key: &signing_key,
+        kdf: kdf_profile.params(),
+        binding: ArchiveBinding::new(&settings.owner(), &signing_key.public_key())?,
+        content_hash: project.content_hash()?,
+        update: args.update,
+    };
 
-    copy_public_dir(&project, &dest_package)?;
-    copy_private_dir(&project, &dest_package)?;
-    copy_decrypt_exes(&dest_package)?;
-    copy_readmes(&project, &settings, &recip_name, &dest_package)?;
+    match (&args.recipient, args.all) {
+        (Some(recip_name), false) => {
+            compiler.compile(recip_name, dest_dir, None)?;
+        }
+        (None, true) => compiler.compile_all(dest_dir)?,
+        _ => bail!("specify either --recipient or --all"),
+    }
 
     Ok(())
 }
 
+/// Everything a run of `compile` shares between the packages it creates
+struct Compiler<'a> {
+    project: &'a KinProject,
+    settings: &'a KinSettings,
+    signing_key: &'a SigningKey,
+    kdf: KdfParams,
+    binding: ArchiveBinding,
+    content_hash: String,
+
+    /// Replace packages already at the destination
+    update: bool,
+}
+
+impl<'a> Compiler<'a> {
+    /// Compiles a backup for every recipient, each in its own directory
+    /// inside `parent_dir`. The archives are only created once.
+    fn compile_all(&self, parent_dir: &PathBuf) -> Result<(), Error> {
+        let mut dest_dirs = Vec::new();
+        for recip in self.settings.recipients.iter() {
+            let dest_dir = parent_dir.join(package_dir_name(&recip.name)?);
+
+            // Check them all up front rather than failing halfway through
+            validation::check_package(self.settings, &recip.name)?;
+            self.check_dest(&recip.name, &dest_dir)?;
+
+            dest_dirs.push((&recip.name, dest_dir));
+        }
+
+        fs::create_dir_all(parent_dir)?;
+
+        let mut first: Option<BackupPackage> = None;
+        for (recip_name, dest_dir) in dest_dirs.iter() {
+            println!("Compiling {}'s backup...", recip_name);
+            let package = self.compile(recip_name, dest_dir, first.as_ref())?;
+            if first.is_none() {
+                first = Some(package);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compiles one recipient's backup. If `compiled_with` is another package
+    /// from this run, its archives are copied instead of created again.
+    fn compile(
+        &self,
+        recip_name: &String,
+        dest_dir: &PathBuf,
+        compiled_with: Option<&BackupPackage>,
+    ) -> Result<BackupPackage, Error> {
+        validation::check_package(self.settings, recip_name)?;
+
+        let old_manifest = match self.check_dest(recip_name, dest_dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let package = self.build(recip_name, dest_dir, compiled_with)?;
+                self.register(recip_name, &package)?;
+                return Ok(package);
+            }
+        };
+
+        let staging_dir = update::staging_dir(dest_dir);
+        if staging_dir.exists() {
+            // Left behind by an update that didn't finish
+            fs::remove_dir_all(&staging_dir)?;
+        }
+
+        // Not registered until it's been swapped in, so the registry never
+        // records a package that isn't on the drive
+        let staged = self.build(recip_name, &staging_dir, compiled_with)?;
+        let package = BackupPackage::from(dest_dir);
+        let replaced = update::replace_changed_files(&package, &old_manifest, &staged)?;
+        self.register(recip_name, &package)?;
+        println!("Updated {} files in {}", replaced, dest_dir.display());
+
+        Ok(package)
+    }
+
+    /// Makes sure a package can be compiled into `dest_dir`. If it's being
+    /// updated, returns the existing package's manifest.
+    fn check_dest(
+        &self,
+        recip_name: &String,
+        dest_dir: &PathBuf,
+    ) -> Result<Option<Manifest>, Error> {
+        if !dest_dir.exists() || !fs::read_dir(dest_dir)?.any(|_| true) {
+            return Ok(None);
+        }
+
+        let existing = BackupPackage::from(dest_dir);
+        if !self.update || !existing.config_file_path().exists() {
+            bail!("{} is not empty", dest_dir.display());
+        }
+
+        let manifest =
+            update::check_existing(self.project, self.signing_key, &existing, recip_name)?;
+        Ok(Some(manifest))
+    }
+
+    fn build(
+        &self,
+        recip_name: &String,
+        dest_dir: &PathBuf,
+        compiled_with: Option<&BackupPackage>,
+    ) -> Result<BackupPackage, Error> {
+        let settings = self.settings;
+
+        let peers = settings.get_peers(recip_name)?;
+        let master_key = match settings.master_key() {
+            Ok(key) => key,
+            Err(e) => bail!("invalid master key: {}", e),
+        };
+
+        let passphrases = peers.iter().map(|x| &x.passphrase).collect();
+        let encrypted_keys = if settings.threshold > 1 {
+            master_key.encrypt_shares(&passphrases, settings.threshold, &self.kdf)?
+        } else {
+            master_key.encrypt(&passphrases, &self.kdf)?
+        };
+
+        let dest_package = BackupPackage::init(
+            dest_dir,
+            recip_name,
+            encrypted_keys,
+            settings.threshold,
+            &self.binding,
+        )?;
+
+        match compiled_with {
+            Some(other) => copy_archives(other, &dest_package)?,
+            None => {
+                copy_public_dir(self.project, &dest_package)?;
+                copy_private_dir(self.project, settings, &dest_package)?;
+            }
+        }
+        copy_decrypt_exes(&dest_package)?;
+        copy_readmes(
+            self.project,
+            settings,
+            recip_name,
+            self.signing_key,
+            &dest_package,
+        )?;
+        sign_package(settings, self.signing_key, &dest_package)?;
+
+        Ok(dest_package)
+    }
+
+    /// Records a package once it's in place at its destination
+    fn register(&self, recip_name: &String, package: &BackupPackage) -> Result<(), Error> {
+        register_package(
+            self.project,
+            self.settings,
+            recip_name,
+            &self.content_hash,
+            package,
+        )
+    }
+}
+
+/// Recipient names end up as directory names with --all
+fn package_dir_name(recip_name: &String) -> Result<&String, Error> {
+    if recip_name == "." || recip_name == ".." || recip_name.contains(|c| c == '/' || c == '\\') {
+        bail!(
+            "\"{}\" can't be used as a directory name; compile this recipient's backup on its own",
+            recip_name
+        );
+    }
+
+    Ok(recip_name)
+}
+
 fn copy_public_dir(src_project: &KinProject, dest_package: &BackupPackage) -> Result<(), Error> {
     let dest_archive_path = dest_package.public_archive_path();
 
     {
         let mut dest_archive = ZipWriter::new(&dest_archive_path)?;
-        zip_dir(
-            &src_project.public_dir(),
-            &mut dest_archive,
-            &PathBuf::new(),
-        )?;
+        zip_files(&src_project.public_files()?, &mut dest_archive)?;
         dest_archive.finish()?;
     }
 
-    platform::set_readonly(&dest_archive_path)?;
+    fsutil::set_readonly(&dest_archive_path)?;
 
     Ok(())
 }
 
-fn copy_private_dir(src_project: &KinProject, dest_package: &BackupPackage) -> Result<(), Error> {
-    if src_project.temp_file().exists() {
-        fs::remove_file(src_project.temp_file())?;
-    }
-
-    let mut temp_archive = ZipWriter::new(&src_project.temp_file())?;
-    zip_dir(
-        &src_project.private_dir(),
-        &mut temp_archive,
-        &PathBuf::new(),
-    )?;
-    temp_archive.finish()?;
-
-    let config = src_project.settings()?;
-    let encryption_key = config.master_key()?;
+fn copy_private_dir(
+    src_project: &KinProject,
+    settings: &KinSettings,
+    dest_package: &BackupPackage,
+) -> Result<(), Error> {
+    let encryption_key = settings.master_key()?;
 
     let dest_path = dest_package.private_archive_path();
 
     {
-        let mut dest_file = OpenOptions::new()
+        let dest_file = OpenOptions::new()
             .create_new(true)
             .write(true)
             .open(&dest_path)?;
 
-        let mut reader = File::open(&src_project.temp_file())?;
-        libsodium::encrypt(&encryption_key, &mut reader, &mut dest_file)?;
-    }
+        // The private files are zipped straight into the encryptor, so no
+        // plaintext copy of them is ever written to disk.
+        let associated_data = dest_package.associated_data()?;
+        let encryptor =
+            Encryptor::new(&encryption_key, &associated_data, BufWriter::new(dest_file))?;
+        let mut archive = ZipWriter::from_writer(encryptor);
+        zip_files(&src_project.private_files()?, &mut archive)?;
 
-    platform::set_readonly(&dest_path)?;
+        archive.finish()?.finish()?;
+    }
 
-    fs::remove_file(src_project.temp_file())?;
+    fsutil::set_readonly(&dest_path)?;
 
     Ok(())
 }
 
-fn zip_dir(
-    source: &PathBuf,
-    dest_archive: &mut ZipWriter,
-    dest_dir: &PathBuf,
-) -> Result<(), Error> {
-    // TODO: Use walkdir crate instead. We already are... it's a third-party
-    // dependency of one of our direct dependencies.
-    // https://crates.io/crates/walkdir
-
-    let contents = fs::read_dir(source)?;
+/// Packages compiled together have identical archives. private.kin is bound
+/// to the archive they share, not to any one package.
+fn copy_archives(src_package: &BackupPackage, dest_package: &BackupPackage) -> Result<(), Error> {
+    let archives = [
+        (
+            src_package.public_archive_path(),
+            dest_package.public_archive_path(),
+        ),
+        (
+            src_package.private_archive_path(),
+            dest_package.private_archive_path(),
+        ),
+    ];
+
+    for (src_path, dest_path) in archives.iter() {
+        fs::copy(src_path, dest_path)?;
+        fsutil::set_readonly(dest_path)?;
+    }
 
-    for item in contents {
-        let item = item?;
-        let metadata = match item.metadata() {
-            Ok(m) => m,
-            Err(e) => bail!("error reading metadata: {}", e),
-        };
+    Ok(())
+}
 
-        if metadata.is_dir() {
-            let dest_dir = dest_dir.join(item.file_name());
-            dest_archive.add_dir(dest_dir.to_str().unwrap())?;
-            zip_dir(&item.path(), dest_archive, &dest_dir)?;
+fn zip_files<W: Write>(
+    files: &[ProjectFile],
+    dest_archive: &mut ZipWriter<W>,
+) -> Result<(), Error> {
+    for file in files.iter() {
+        if file.is_dir {
+            dest_archive.add_dir(&file.name)?;
         } else {
-            let dest_path = dest_dir.join(item.file_name());
-            let dest_path = dest_path.to_str().unwrap();
+            info!("zipping {} to {}...", file.path.display(), file.name);
+            dest_archive.add_file(&file.path, &file.name)?;
+        }
+    }
+
+    Ok(())
+}
 
-            info!(
-                "zipping {} to {}...",
-                item.path().to_str().unwrap(),
-                dest_path
-            );
+/// Lists what each archive would contain, after applying the project's
+/// .kinignore files
+fn print_archive_contents(project: &KinProject) -> Result<(), Error> {
+    let archives = [
+        ("public.zip", project.public_files()?),
+        ("private.kin", project.private_files()?),
+    ];
+
+    for (i, (archive, files)) in archives.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
 
-            dest_archive.add_file(&item.path(), dest_path)?;
+        println!("{} would contain:", archive);
+        for file in files.iter().filter(|x| !x.is_dir) {
+            println!("  {}", file.name);
         }
     }
 
@@ -136,8 +352,7 @@ fn zip_dir(
 fn copy_decrypt_exes(dest_package: &BackupPackage) -> Result<(), Error> {
     fsutil::ensure_empty_dir(&dest_package.decrypt_exe_dir())?;
 
-    let executable_archive_bytes = include_bytes!("decrypt_executables.zip").to_vec();
-    zip::extract(&executable_archive_bytes, &dest_package.decrypt_exe_dir())?;
+    zip::extract(DECRYPT_EXECUTABLES, &dest_package.decrypt_exe_dir())?;
 
     Ok(())
 }
@@ -146,6 +361,7 @@ fn copy_readmes(
     project: &KinProject,
     settings: &KinSettings,
     recipient: &String,
+    signing_key: &SigningKey,
     dest_package: &BackupPackage,
 ) -> Result<(), Error> {
     let peers = settings
@@ -165,6 +381,9 @@ fn copy_readmes(
         recipient: recipient.name.clone(),
         passphrase: recipient.passphrase.clone(),
         peers: peers,
+        threshold: settings.threshold,
+        multiple_required: settings.threshold > 1,
+        owner_fingerprint: signing_key.public_key().fingerprint(),
     };
 
     readme::render(
@@ -172,40 +391,61 @@ fn copy_readmes(
         &model,
         &dest_package.overview_readme_path(),
     )?;
-    platform::set_readonly(&dest_package.overview_readme_path())?;
+    fsutil::set_readonly(&dest_package.overview_readme_path())?;
 
     readme::render(
         &project.decrypt_readme_template(),
         &model,
         &dest_package.decrypt_readme_path(),
     )?;
-    platform::set_readonly(&dest_package.decrypt_readme_path())?;
+    fsutil::set_readonly(&dest_package.decrypt_readme_path())?;
 
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-mod platform {
-    use kin_core::Error;
-    use std::fs;
-    use std::os::unix::fs::PermissionsExt;
-    use std::path::PathBuf;
-
-    pub fn set_readonly(path: &PathBuf) -> Result<(), Error> {
-        // Set read-only permissions for user, group, and others.
-        let perms = PermissionsExt::from_mode(0o444);
-        fs::set_permissions(path, perms)?;
-        Ok(())
-    }
+/// Writes a manifest of everything in the package, signed by the owner. Must
+/// come last, after every other file is in place.
+fn sign_package(
+    settings: &KinSettings,
+    signing_key: &SigningKey,
+    dest_package: &BackupPackage,
+) -> Result<(), Error> {
+    let public_key = signing_key.public_key();
+    let manifest = Manifest::build(dest_package, &settings.owner(), &public_key)?;
+    manifest.write(dest_package, signing_key)?;
+
+    println!(
+        "Package signed with owner key fingerprint {}",
+        public_key.fingerprint()
+    );
+
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-mod platform {
-    use kin_core::Error;
-    use std::path::PathBuf;
+/// Remembers the package so the owner can find it again when it needs to be
+/// reissued
+fn register_package(
+    project: &KinProject,
+    settings: &KinSettings,
+    recipient: &String,
+    content_hash: &String,
+    package: &BackupPackage,
+) -> Result<(), Error> {
+    let peers = settings
+        .get_peers(recipient)?
+        .iter()
+        .map(|p| p.name.clone())
+        .collect();
 
-    pub fn set_readonly(_path: &PathBuf) -> Result<(), Error> {
-        // TODO: Set readonly flag on file
-        Ok(())
-    }
+    let mut registry = project.registry()?;
+    registry.record(IssuedPackage {
+        package_id: package.package_id()?,
+        recipient: recipient.clone(),
+        created: package.created()?,
+        peers: peers,
+        key_generation: settings.master_key_generation(),
+        content_hash: content_hash.clone(),
+        stale: None,
+    });
+    project.write_registry(&registry)
 }
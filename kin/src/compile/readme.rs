@@ -1,6 +1,6 @@
 use kin_core::templating;
 use kin_core::templating::mustache;
-use kin_core::{bail, Error};
+use kin_core::{bail, Error, SecretString};
 use serde::Serialize;
 use std::fs::File;
 use std::io::{BufReader, Read};
@@ -10,8 +10,11 @@ use std::path::PathBuf;
 pub struct ReadmeModel {
     pub owner: String,
     pub recipient: String,
-    pub passphrase: String,
+    pub passphrase: SecretString,
     pub peers: Vec<PeerModel>,
+    pub threshold: u8,
+    pub multiple_required: bool,
+    pub owner_fingerprint: String,
 }
 
 #[derive(Serialize)]
@@ -24,6 +27,12 @@ pub fn render(
     model: &ReadmeModel,
     dest_path: &PathBuf,
 ) -> Result<(), Error> {
+    let md_content = render_markdown(md_template_path, model)?;
+    templating::render_html(&md_content, &dest_path)
+}
+
+/// Fills in a readme template, without turning it into HTML
+pub fn render_markdown(md_template_path: &PathBuf, model: &ReadmeModel) -> Result<String, Error> {
     let mut md_template_text = String::new();
 
     {
@@ -31,7 +40,7 @@ pub fn render(
             Ok(file) => file,
             Err(e) => bail!(
                 "unable to open template file {}: {}",
-                md_template_path.to_str().unwrap(),
+                md_template_path.display(),
                 e
             ),
         };
@@ -42,7 +51,7 @@ pub fn render(
         if result.is_err() {
             bail!(
                 "unable to read template file {}: {}",
-                md_template_path.to_str().unwrap(),
+                md_template_path.display(),
                 result.err().unwrap()
             );
         }
@@ -53,10 +62,8 @@ pub fn render(
         Err(e) => bail!("unable to compile mustache template: {}", e),
     };
 
-    let md_content = match md_template.render_to_string(model) {
-        Ok(content) => content,
+    match md_template.render_to_string(model) {
+        Ok(content) => Ok(content),
         Err(e) => bail!("unable to render mustache template: {}", e),
-    };
-
-    templating::render_html(&md_content, &dest_path)
+    }
 }
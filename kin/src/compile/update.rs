@@ -0,0 +1,135 @@
+use kin_core::libsodium;
+use kin_core::libsodium::SigningKey;
+use kin_core::{bail, BackupPackage, Error, KinProject, Manifest};
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+
+/// Where an updated package is assembled before it replaces the old one.
+/// It's inside the package, so it's on the same drive, where moving a file
+/// into place is atomic.
+const STAGING_DIR: &str = ".kin-update";
+
+pub fn staging_dir(dest_dir: &PathBuf) -> PathBuf {
+    dest_dir.join(STAGING_DIR)
+}
+
+/// Makes sure the package at `existing` was compiled from this project for
+/// `recipient`. Returns its manifest, which says which files are Kin's.
+pub fn check_existing(
+    project: &KinProject,
+    signing_key: &SigningKey,
+    existing: &BackupPackage,
+    recipient: &String,
+) -> Result<Manifest, Error> {
+    let dir = existing.path();
+    let dir = dir.display();
+
+    if !existing.manifest_path().exists() {
+        bail!(
+            "{} was compiled by an older version of Kin, so it can't be updated; compile into an empty directory instead",
+            dir
+        );
+    }
+
+    let manifest = match Manifest::read_verified(existing) {
+        Ok(manifest) => manifest,
+        Err(e) => bail!("unable to update {}: {}", dir, e),
+    };
+
+    if manifest.public_key()? != signing_key.public_key() {
+        bail!("{} was compiled from a different project", dir);
+    }
+
+    match project.registry()?.compiled_for(existing)? {
+        Some(ref name) if name == recipient => Ok(manifest),
+        Some(name) => bail!("{} holds {}'s backup, not {}'s", dir, name, recipient),
+        None => bail!("{} doesn't record who it was compiled for", dir),
+    }
+}
+
+/// Moves each file from `staged` into `existing` unless it's already there,
+/// and removes files that only the old package had. Anything else in the
+/// directory is left alone. Returns how many files were replaced.
+pub fn replace_changed_files(
+    existing: &BackupPackage,
+    old_manifest: &Manifest,
+    staged: &BackupPackage,
+) -> Result<usize, Error> {
+    match replace_files(existing, old_manifest, staged) {
+        Ok(replaced) => Ok(replaced),
+        Err(e) => bail!(
+            "the update of {} didn't finish ({}), so the backup there may not open; run `kin compile --update` again to finish it",
+            existing.path().display(),
+            e
+        ),
+    }
+}
+
+fn replace_files(
+    existing: &BackupPackage,
+    old_manifest: &Manifest,
+    staged: &BackupPackage,
+) -> Result<usize, Error> {
+    let new_manifest = Manifest::read_verified(staged)?;
+
+    // private.kin only decrypts along with the config.json it was compiled
+    // with, so those two are moved first, one right after the other
+    let bound = [staged.config_file_path(), staged.private_archive_path()];
+    let mut entries: Vec<_> = new_manifest.files.iter().collect();
+    entries.sort_by_key(|entry| !bound.contains(&staged.path().join(&entry.path)));
+
+    let mut replaced = 0;
+    for entry in entries {
+        let dest_path = existing.path().join(&entry.path);
+        if hash_file(&dest_path)? == Some(entry.sha256.clone()) {
+            continue;
+        }
+
+        replace_file(&staged.path().join(&entry.path), &dest_path)?;
+        replaced += 1;
+    }
+
+    let removed = old_manifest
+        .files
+        .iter()
+        .filter(|old| !new_manifest.files.iter().any(|new| new.path == old.path));
+    for entry in removed {
+        let path = existing.path().join(&entry.path);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    // The manifest goes last. Until its signature is in place, the package
+    // reads as tampered with rather than as intact.
+    replace_file(&staged.manifest_path(), &existing.manifest_path())?;
+    replace_file(
+        &staged.manifest_signature_path(),
+        &existing.manifest_signature_path(),
+    )?;
+
+    fs::remove_dir_all(staged.path())?;
+
+    Ok(replaced)
+}
+
+fn hash_file(path: &PathBuf) -> Result<Option<String>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path)?;
+    Ok(Some(libsodium::to_hex(&libsodium::sha256_reader(
+        &mut file,
+    )?)))
+}
+
+fn replace_file(src: &PathBuf, dest: &PathBuf) -> Result<(), Error> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::rename(src, dest)?;
+    Ok(())
+}
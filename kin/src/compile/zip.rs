@@ -1,91 +1,389 @@
-use kin_core::Error;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use kin_core::{bail, Error};
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{Cursor, Read, Write};
 use std::path::PathBuf;
-use zip::write::FileOptions;
-use zip::{CompressionMethod, ZipArchive, ZipWriter as InternalZipWriter};
+use zip::{DateTime, ZipArchive};
 
-pub fn extract(compressed_data: &Vec<u8>, dest_directory: &PathBuf) -> Result<(), Error> {
+pub fn extract(compressed_data: &[u8], dest_directory: &PathBuf) -> Result<(), Error> {
     let reader = Cursor::new(compressed_data);
-    let mut archive = ZipArchive::new(reader).expect("unable to unzip decrypt executable archive");
+    let mut archive = match ZipArchive::new(reader) {
+        Ok(archive) => archive,
+        Err(e) => bail!("unable to unzip decrypt executable archive: {}", e),
+    };
 
     for i in 0..archive.len() {
-        let mut source_file = archive.by_index(i).expect(&format!(
-            "unable to extract file {} from decrypt executable archive",
-            i
-        ));
+        let mut source_file = match archive.by_index(i) {
+            Ok(file) => file,
+            Err(e) => bail!(
+                "unable to extract file {} from decrypt executable archive: {}",
+                i,
+                e
+            ),
+        };
 
         let dest_path = dest_directory.join(source_file.name());
-        let mut dest_file = OpenOptions::new()
+        let dest_file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(&dest_path)
-            .expect(&format!(
-                "unable to create file {}",
-                dest_path.to_str().unwrap()
-            ));
+            .open(&dest_path);
+        let mut dest_file = match dest_file {
+            Ok(file) => file,
+            Err(e) => bail!("unable to create file {}: {}", dest_path.display(), e),
+        };
 
         std::io::copy(&mut source_file, &mut dest_file)?;
 
-        let mode = source_file.unix_mode();
-        if mode.is_some() {
-            platform::set_mode(&dest_path, mode.unwrap()).expect(&format!(
-                "unable to set mode on {}",
-                dest_path.to_str().unwrap()
-            ));
+        if let Some(mode) = source_file.unix_mode() {
+            if let Err(e) = platform::set_mode(&dest_path, mode) {
+                bail!("unable to set mode on {}: {}", dest_path.display(), e);
+            }
         }
     }
 
     Ok(())
 }
 
-pub struct ZipWriter {
-    internal: InternalZipWriter<File>,
+/// Names and sizes of the files in an archive, read all the way through so
+/// that any corruption shows up
+pub fn list(compressed_data: &[u8]) -> Result<Vec<(String, u64)>, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(compressed_data))?;
+
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let size = std::io::copy(&mut file, &mut std::io::sink())?;
+        files.push((file.name().to_string(), size));
+    }
+
+    Ok(files)
 }
 
-impl ZipWriter {
-    pub fn new(archive_path: &PathBuf) -> Result<ZipWriter, Error> {
+/// Writes a zip archive to a stream that can only go forward, like an
+/// encryptor, without holding any file in memory.
+///
+/// Each entry's header comes before its data but records the data's CRC and
+/// compressed size. The zip reader that decrypts the archive doesn't support
+/// entries that put those after the data instead, so each file is read twice:
+/// once to work them out, and again to write it.
+pub struct ZipWriter<W: Write> {
+    inner: CountingWriter<W>,
+    entries: Vec<Entry>,
+    modified: DateTime,
+
+    /// Sizes and offsets from this one on are recorded in zip64 fields
+    zip64_limit: u64,
+}
+
+/// What the central directory needs to know about an entry
+struct Entry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+    unix_mode: u32,
+}
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06054b50;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Bit 11: file names are UTF-8
+const UTF8_NAMES: u16 = 1 << 11;
+
+impl ZipWriter<File> {
+    pub fn new(archive_path: &PathBuf) -> Result<ZipWriter<File>, Error> {
         let file = OpenOptions::new()
             .create_new(true)
             .write(true)
             .open(archive_path)?;
 
-        let writer = ZipWriter {
-            internal: InternalZipWriter::new(file),
-        };
+        Ok(ZipWriter::from_writer(file))
+    }
+}
 
-        Ok(writer)
+impl<W: Write> ZipWriter<W> {
+    pub fn from_writer(writer: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner: CountingWriter::new(writer),
+            entries: Vec::new(),
+            modified: DateTime::from_time(time::now()).unwrap_or_default(),
+            zip64_limit: 0xFFFFFFFF,
+        }
     }
 
     pub fn add_dir(&mut self, archive_path: &str) -> Result<(), Error> {
-        self.internal
-            .add_directory(archive_path, FileOptions::default())?;
+        let mut name = archive_path.to_string();
+        if !name.ends_with('/') {
+            name.push('/');
+        }
+
+        let entry = Entry {
+            name: name,
+            method: STORED,
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            offset: self.inner.written,
+            unix_mode: 0o040755,
+        };
+
+        self.write_local_header(&entry)?;
+        self.entries.push(entry);
         Ok(())
     }
 
     pub fn add_file(&mut self, src_path: &PathBuf, archive_path: &str) -> Result<(), Error> {
-        let mut file = OpenOptions::new().read(true).open(src_path)?;
+        let file = OpenOptions::new().read(true).open(src_path)?;
+        let (crc32, size, compressed_size) = match deflate(file, io::sink()) {
+            Ok(measured) => measured,
+            Err(e) => bail!("unable to read {}: {}", src_path.display(), e),
+        };
 
-        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
-        self.internal.start_file(archive_path, options)?;
+        let entry = Entry {
+            name: archive_path.to_string(),
+            method: DEFLATED,
+            crc32: crc32,
+            compressed_size: compressed_size,
+            size: size,
+            offset: self.inner.written,
+            unix_mode: 0o100644,
+        };
+        self.write_local_header(&entry)?;
+
+        let file = OpenOptions::new().read(true).open(src_path)?;
+        let written = match deflate(file, &mut self.inner) {
+            Ok(written) => written,
+            Err(e) => bail!("unable to zip {}: {}", src_path.display(), e),
+        };
+        if written != (crc32, size, compressed_size) {
+            bail!("{} changed while it was being zipped", src_path.display());
+        }
+
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        let start = self.inner.written;
+        for entry in self.entries.iter() {
+            let header = entry.central_header(&self.modified, self.zip64_limit);
+            self.inner.write_all(&header)?;
+        }
+        let size = self.inner.written - start;
+        let count = self.entries.len() as u64;
+
+        let mut end = Vec::new();
+        let zip64 = count >= 0xFFFF || size >= self.zip64_limit || start >= self.zip64_limit;
+        if zip64 {
+            let zip64_end = self.inner.written;
+            put_u32(&mut end, ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE);
+            put_u64(&mut end, 44); // The size of the rest of this record
+            put_u16(&mut end, 45); // Version made by
+            put_u16(&mut end, 45); // Version needed to extract
+            put_u32(&mut end, 0); // This disk
+            put_u32(&mut end, 0); // The disk the central directory starts on
+            put_u64(&mut end, count); // Entries on this disk
+            put_u64(&mut end, count);
+            put_u64(&mut end, size);
+            put_u64(&mut end, start);
+
+            put_u32(&mut end, ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE);
+            put_u32(&mut end, 0); // The disk the zip64 end record is on
+            put_u64(&mut end, zip64_end);
+            put_u32(&mut end, 1); // Total disks
+        }
+
+        put_u32(&mut end, CENTRAL_DIRECTORY_END_SIGNATURE);
+        put_u16(&mut end, 0); // This disk
+        put_u16(&mut end, 0); // The disk the central directory starts on
+        put_u16(&mut end, count.min(0xFFFF) as u16); // Entries on this disk
+        put_u16(&mut end, count.min(0xFFFF) as u16);
+        put_u32(&mut end, if zip64 { 0xFFFFFFFF } else { size as u32 });
+        put_u32(&mut end, if zip64 { 0xFFFFFFFF } else { start as u32 });
+        put_u16(&mut end, 0); // Comment length
+        self.inner.write_all(&end)?;
+
+        Ok(self.inner.inner)
+    }
+
+    /// Records sizes and offsets in zip64 fields from `limit` on, so tests
+    /// don't need archives over 4 GiB
+    #[cfg(test)]
+    fn with_zip64_limit(writer: W, limit: u64) -> ZipWriter<W> {
+        ZipWriter {
+            zip64_limit: limit,
+            ..ZipWriter::from_writer(writer)
+        }
+    }
+
+    fn write_local_header(&mut self, entry: &Entry) -> io::Result<()> {
+        let zip64 = entry.size >= self.zip64_limit || entry.compressed_size >= self.zip64_limit;
+
+        let mut header = Vec::new();
+        put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+        put_u16(&mut header, if zip64 { 45 } else { 20 }); // Version needed to extract
+        put_u16(&mut header, UTF8_NAMES);
+        put_u16(&mut header, entry.method);
+        put_u16(&mut header, self.modified.timepart());
+        put_u16(&mut header, self.modified.datepart());
+        put_u32(&mut header, entry.crc32);
+        if zip64 {
+            // Both sizes go in the zip64 field, uncompressed first
+            put_u32(&mut header, 0xFFFFFFFF);
+            put_u32(&mut header, 0xFFFFFFFF);
+        } else {
+            put_u32(&mut header, entry.compressed_size as u32);
+            put_u32(&mut header, entry.size as u32);
+        }
+        put_u16(&mut header, entry.name.len() as u16);
+        put_u16(&mut header, if zip64 { 20 } else { 0 }); // Extra field length
+        header.extend_from_slice(entry.name.as_bytes());
+        if zip64 {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, entry.size);
+            put_u64(&mut header, entry.compressed_size);
+        }
+
+        self.inner.write_all(&header)
+    }
+}
+
+impl Entry {
+    fn central_header(&self, modified: &DateTime, zip64_limit: u64) -> Vec<u8> {
+        // Each value that doesn't fit is replaced with 0xFFFFFFFF and recorded
+        // in the zip64 field instead, in this order
+        let mut zip64 = Vec::new();
+        let mut fit = |value: u64| {
+            if value >= zip64_limit {
+                put_u64(&mut zip64, value);
+                0xFFFFFFFF
+            } else {
+                value as u32
+            }
+        };
+        let size = fit(self.size);
+        let compressed_size = fit(self.compressed_size);
+        let offset = fit(self.offset);
+        let version = if zip64.is_empty() { 20 } else { 45 };
+
+        let mut header = Vec::new();
+        put_u32(&mut header, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        put_u16(&mut header, (3 << 8) | version); // Made by Unix
+        put_u16(&mut header, version); // Version needed to extract
+        put_u16(&mut header, UTF8_NAMES);
+        put_u16(&mut header, self.method);
+        put_u16(&mut header, modified.timepart());
+        put_u16(&mut header, modified.datepart());
+        put_u32(&mut header, self.crc32);
+        put_u32(&mut header, compressed_size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(
+            &mut header,
+            if zip64.is_empty() {
+                0
+            } else {
+                zip64.len() as u16 + 4
+            },
+        );
+        put_u16(&mut header, 0); // Comment length
+        put_u16(&mut header, 0); // The disk the entry starts on
+        put_u16(&mut header, 0); // Internal attributes
+        put_u32(&mut header, self.unix_mode << 16);
+        put_u32(&mut header, offset);
+        header.extend_from_slice(self.name.as_bytes());
+        if !zip64.is_empty() {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, zip64.len() as u16);
+            header.extend_from_slice(&zip64);
+        }
+
+        header
+    }
+}
+
+/// Compresses `input` into `output`. Returns the CRC and size of the input,
+/// and the size of the compressed output.
+fn deflate<R: Read, W: Write>(mut input: R, output: W) -> io::Result<(u32, u64, u64)> {
+    let mut output = CountingWriter::new(output);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0;
+
+    {
+        let mut encoder = DeflateEncoder::new(&mut output, Compression::default());
 
         const BUF_SIZE: usize = 16384; // 16 KiB
         let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
-        let mut amount_read = file.read(&mut buf)?;
+        loop {
+            let amount_read = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(amount_read) => amount_read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
 
-        while amount_read > 0 {
-            self.internal.write_all(&buf[0..amount_read])?;
-            amount_read = file.read(&mut buf)?;
+            hasher.update(&buf[0..amount_read]);
+            encoder.write_all(&buf[0..amount_read])?;
+            size += amount_read as u64;
         }
 
-        Ok(())
+        encoder.finish()?;
     }
 
-    pub fn finish(&mut self) -> Result<(), Error> {
-        self.internal.finish()?;
-        Ok(())
+    Ok((hasher.finalize(), size, output.written))
+}
+
+/// Keeps track of how much has been written, since the stream can't be
+/// asked where it is
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter {
+            inner: inner,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
 }
 
 #[cfg(target_os = "linux")]
@@ -112,3 +410,88 @@ mod platform {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kin_core::test_support::TempDir;
+    use std::fs;
+
+    /// Zips a directory, an empty file, a small one, and one that's too
+    /// random to compress
+    fn write_archive(temp: &TempDir, zip64_limit: u64) -> Vec<u8> {
+        kin_core::libsodium::init().unwrap();
+
+        let mut random = vec![0; 100000];
+        kin_core::libsodium::randombytes_into(&mut random);
+
+        let files = [
+            ("empty.txt", Vec::new()),
+            ("hello.txt", b"hello hello hello hello".to_vec()),
+            ("random.bin", random),
+        ];
+        for (name, contents) in files.iter() {
+            fs::write(temp.path().join(name), contents).unwrap();
+        }
+
+        let mut archive = ZipWriter::with_zip64_limit(Vec::new(), zip64_limit);
+        archive.add_dir("sub").unwrap();
+        for (name, _) in files.iter() {
+            archive
+                .add_file(&temp.path().join(name), &format!("sub/{}", name))
+                .unwrap();
+        }
+        archive.finish().unwrap()
+    }
+
+    fn assert_readable(temp: &TempDir, archive: Vec<u8>) {
+        let expected = ["sub/", "sub/empty.txt", "sub/hello.txt", "sub/random.bin"];
+
+        // Reading the central directory, the way most zip programs do
+        let mut seekable = ZipArchive::new(Cursor::new(archive.clone())).unwrap();
+        assert_eq!(seekable.len(), expected.len());
+        for (i, name) in expected.iter().enumerate() {
+            let mut entry = seekable.by_index(i).unwrap();
+            assert_eq!(entry.name(), *name);
+
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            if !name.ends_with('/') {
+                assert!(contents == fs::read(temp.path().join(&name[4..])).unwrap());
+            }
+        }
+
+        // Reading one entry after another, the way decrypt does
+        let mut stream = Cursor::new(archive);
+        for name in expected.iter() {
+            let mut entry = zip::read::read_zipfile_from_stream(&mut stream)
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.name(), *name);
+
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            if !name.ends_with('/') {
+                assert!(contents == fs::read(temp.path().join(&name[4..])).unwrap());
+            }
+        }
+        assert!(zip::read::read_zipfile_from_stream(&mut stream)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn archives_can_be_read_back() {
+        let temp = TempDir::new();
+        let archive = write_archive(&temp, 0xFFFFFFFF);
+        assert_readable(&temp, archive);
+    }
+
+    #[test]
+    fn zip64_archives_can_be_read_back() {
+        let temp = TempDir::new();
+        let archive = write_archive(&temp, 0);
+        assert_readable(&temp, archive);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::compile::readme::{render_markdown, PeerModel, ReadmeModel};
+use crate::compile::{zip, DECRYPT_EXECUTABLES};
+use crate::report::Report;
+use kin_core::{validation, DoctorArgs, Error, KinProject, KinSettings, SecretString};
+use std::fs::File;
+
+/// Stands in for a recipient's passphrase when rendering the readme
+/// templates, so it's easy to spot in the result
+const SAMPLE_PASSPHRASE: &str = "sample-passphrase-for-kin-doctor";
+
+pub fn run(args: &DoctorArgs) -> Result<(), Error> {
+    let project = match &args.project_dir {
+        Some(dir) => KinProject::from(&dir),
+        None => KinProject::from(&std::env::current_dir()?),
+    };
+
+    let mut report = Report::new(
+        "project",
+        "No problems found. This project is ready to compile.",
+    );
+
+    println!("Checking settings...");
+    if !project.config_file().exists() {
+        report.problem_with_fix(
+            "this isn't a Kin project",
+            "run `kin init`, or pass the project directory with --project-dir",
+        );
+        return report.finish();
+    }
+    let settings = check_settings(&project, &mut report);
+
+    if let Some(settings) = &settings {
+        println!("Checking recipients...");
+        check_recipients(settings, &mut report);
+    }
+
+    println!("Checking readme templates...");
+    check_templates(&project, settings.as_ref(), &mut report);
+
+    println!("Checking files...");
+    check_files(&project, &mut report);
+
+    println!("Checking decrypt programs...");
+    check_decrypt_exes(&mut report);
+
+    report.finish()
+}
+
+/// Returns the settings if they can be read at all, even if some of what's
+/// in them is broken
+fn check_settings(project: &KinProject, report: &mut Report) -> Option<KinSettings> {
+    const RESTORE: &str =
+        "if you edited .kin/config.json by hand, undo the change or restore it from a copy";
+
+    let settings = match project.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            report.problem_with_fix(&format!("unable to read settings: {}", e), RESTORE);
+            return None;
+        }
+    };
+    report.ok("settings can be read");
+
+    match settings.master_key() {
+        Ok(_) => report.ok(&format!(
+            "master key {} is valid",
+            settings.master_key_generation()
+        )),
+        Err(e) => report.problem_with_fix(
+            &format!("the master key is invalid: {}", e),
+            &format!("{}, or run `kin rekey` and reissue every backup", RESTORE),
+        ),
+    }
+
+    for retired in settings.retired_master_keys().iter() {
+        if let Err(e) = retired.master_key() {
+            report.problem_with_fix(
+                &format!(
+                    "retired master key {} is invalid: {}",
+                    retired.generation, e
+                ),
+                RESTORE,
+            );
+        }
+    }
+
+    match settings.signing_key() {
+        Ok(Some(_)) => report.ok("owner signing key is valid"),
+        Ok(None) => {
+            report.ok("there's no owner signing key yet; one is generated on the next compile")
+        }
+        Err(e) => {
+            report.problem_with_fix(&format!("the owner signing key is invalid: {}", e), RESTORE)
+        }
+    }
+
+    if let Err(e) = project.registry() {
+        report.problem_with_fix(
+            &format!("unable to read the record of compiled backups: {}", e),
+            "fix .kin/packages.json, or delete it to forget which backups have been compiled",
+        );
+    }
+
+    Some(settings)
+}
+
+fn check_recipients(settings: &KinSettings, report: &mut Report) {
+    let names: Vec<String> = settings.recipients.iter().map(|x| x.name.clone()).collect();
+
+    if let Err(e) = KinSettings::check_recipient_names(&names) {
+        report.problem_with_fix(
+            &e.to_string(),
+            "give each recipient a unique name with `kin recipient rename`",
+        );
+        return;
+    }
+
+    let warnings = validation::recipient_warnings(&names, settings.threshold);
+    for warning in warnings.iter() {
+        report.problem_with_fix(warning, "add more with `kin recipient add`");
+    }
+    if !warnings.is_empty() {
+        return;
+    }
+
+    let mut problems = 0;
+    for (i, recip) in settings.recipients.iter().enumerate() {
+        let rotate = format!(
+            "give them a new one with `kin recipient rotate \"{}\"`",
+            recip.name
+        );
+
+        if recip.passphrase.len() == 0 {
+            report.problem_with_fix(&format!("{} has a blank passphrase", recip.name), &rotate);
+            problems += 1;
+        }
+
+        let same = settings.recipients[i + 1..]
+            .iter()
+            .filter(|x| x.passphrase.len() > 0 && x.passphrase == recip.passphrase);
+        for other in same {
+            report.problem_with_fix(
+                &format!(
+                    "{} and {} have the same passphrase, so each can unlock their own backup",
+                    recip.name, other.name
+                ),
+                &rotate,
+            );
+            problems += 1;
+        }
+
+        if let Err(e) = validation::check_package(settings, &recip.name) {
+            report.problem_with_fix(&e.to_string(), "add more with `kin recipient add`");
+            problems += 1;
+        }
+    }
+
+    if problems == 0 {
+        report.ok(&format!(
+            "{} recipients, whose backups can each be unlocked by {} of the others",
+            names.len(),
+            validation::passphrases_needed(settings.threshold)
+        ));
+    }
+}
+
+/// Renders each template against sample data, the way compile would
+fn check_templates(project: &KinProject, settings: Option<&KinSettings>, report: &mut Report) {
+    let model = sample_model(settings);
+    let templates = [
+        (project.overview_readme_template(), true),
+        (project.decrypt_readme_template(), false),
+    ];
+
+    for (path, shows_passphrase) in templates.iter() {
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        if !path.exists() {
+            report.problem_with_fix(
+                &format!("{} is missing", name),
+                "copy it from a new project created with `kin init`",
+            );
+            continue;
+        }
+
+        match render_markdown(path, &model) {
+            Ok(ref rendered) if *shows_passphrase && !rendered.contains(SAMPLE_PASSPHRASE) => {
+                report.problem_with_fix(
+                    &format!("{} doesn't show the backup holder their passphrase", name),
+                    "add {{passphrase}} to it",
+                )
+            }
+            Ok(_) => report.ok(&format!("{} renders", name)),
+            Err(e) => report.problem_with_fix(
+                &format!("{}: {}", name, e),
+                &format!("fix the mustache syntax in {}", name),
+            ),
+        }
+    }
+}
+
+fn sample_model(settings: Option<&KinSettings>) -> ReadmeModel {
+    let (owner, names, threshold) = match settings {
+        Some(settings) if !settings.recipients.is_empty() => (
+            settings.owner(),
+            settings.recipients.iter().map(|x| x.name.clone()).collect(),
+            settings.threshold,
+        ),
+        _ => (
+            String::from("Owen"),
+            vec![
+                String::from("Alice"),
+                String::from("Bob"),
+                String::from("Chuck"),
+            ],
+            1,
+        ),
+    };
+
+    ReadmeModel {
+        owner: owner,
+        recipient: names[0].clone(),
+        passphrase: SecretString::copy_from(SAMPLE_PASSPHRASE),
+        peers: names[1..]
+            .iter()
+            .map(|x| PeerModel { name: x.clone() })
+            .collect(),
+        threshold: threshold,
+        multiple_required: threshold > 1,
+        owner_fingerprint: String::from("sample fingerprint"),
+    }
+}
+
+/// Makes sure everything that would go into the archives can be read
+fn check_files(project: &KinProject, report: &mut Report) {
+    let listings = [
+        ("public", project.public_dir(), project.public_files()),
+        ("private", project.private_dir(), project.private_files()),
+    ];
+
+    for (label, dir, listing) in listings.iter() {
+        if !dir.is_dir() {
+            report.problem_with_fix(
+                &format!("the {} folder is missing", label),
+                &format!("create {}", dir.display()),
+            );
+            continue;
+        }
+
+        let files = match listing {
+            Ok(files) => files,
+            Err(e) => {
+                report.problem_with_fix(
+                    &e.to_string(),
+                    "fix the file, or leave it out by adding it to a .kinignore file",
+                );
+                continue;
+            }
+        };
+
+        let mut unreadable = 0;
+        for file in files.iter().filter(|x| !x.is_dir) {
+            if let Err(e) = File::open(&file.path) {
+                report.problem_with_fix(
+                    &format!("unable to read {}: {}", file.path.display(), e),
+                    "check its permissions, or leave it out by adding it to a .kinignore file",
+                );
+                unreadable += 1;
+            }
+        }
+
+        if unreadable == 0 {
+            report.ok(&format!(
+                "{} {} file(s) can be read",
+                files.iter().filter(|x| !x.is_dir).count(),
+                label
+            ));
+        }
+    }
+}
+
+fn check_decrypt_exes(report: &mut Report) {
+    const REBUILD: &str = "rebuild kin with build.sh, which builds the decrypt programs first";
+
+    let exes = match zip::list(DECRYPT_EXECUTABLES) {
+        Ok(exes) => exes,
+        Err(e) => {
+            report.problem_with_fix(
+                &format!("the decrypt programs built into kin are damaged: {}", e),
+                REBUILD,
+            );
+            return;
+        }
+    };
+
+    if exes.is_empty() {
+        report.problem_with_fix("no decrypt programs are built into kin", REBUILD);
+        return;
+    }
+
+    for (name, _) in exes.iter().filter(|(_, size)| *size == 0) {
+        report.problem_with_fix(
+            &format!("the decrypt program {} built into kin is empty", name),
+            REBUILD,
+        );
+    }
+
+    let names: Vec<&str> = exes.iter().map(|(name, _)| name.as_str()).collect();
+    report.ok(&format!("decrypt programs: {}", names.join(", ")));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kin_core::test_support::settings;
+
+    fn count_problems(settings: &KinSettings) -> usize {
+        let mut report = Report::new("project", "");
+        check_recipients(settings, &mut report);
+        report.problems()
+    }
+
+    #[test]
+    fn recipient_problems() {
+        assert_eq!(
+            count_problems(&settings(&[("Alice", "a"), ("Bob", "b")], 1)),
+            0
+        );
+        assert_eq!(count_problems(&settings(&[("Alice", "a")], 1)), 1);
+        assert_eq!(
+            count_problems(&settings(&[("Alice", "a"), ("Bob", "b")], 2)),
+            1
+        );
+        assert_eq!(
+            count_problems(&settings(&[("Alice", "a"), ("alice", "b")], 1)),
+            1
+        );
+
+        // Blank, and leaves Alice's backup without a usable key slot
+        assert_eq!(
+            count_problems(&settings(&[("Alice", "a"), ("Bob", "")], 1)),
+            2
+        );
+
+        assert_eq!(
+            count_problems(&settings(
+                &[("Alice", "a"), ("Bob", "b"), ("Chuck", "b")],
+                1
+            )),
+            1
+        );
+    }
+
+    #[test]
+    fn sample_model() {
+        let model = super::sample_model(Some(&settings(&[("Alice", "a"), ("Bob", "b")], 1)));
+        assert_eq!(model.recipient, "Alice");
+        assert_eq!(model.peers.len(), 1);
+
+        let model = super::sample_model(None);
+        assert_eq!(model.owner, "Owen");
+        assert_eq!(model.peers.len(), 2);
+    }
+}
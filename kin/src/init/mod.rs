@@ -1,10 +1,15 @@
-use kin_core::libsodium;
-use kin_core::ui;
-use kin_core::{Error, InitArgs, KinProject, KinRecipient, KinSettings};
+use crate::passphrase::random_passphrase;
+use kin_core::{ui, validation};
+use kin_core::{Error, InitArgs, KinProject, KinRecipient, KinSettings, SecretString};
 use std::fs::File;
 use std::io::Write;
 
 pub fn run(args: &InitArgs) -> Result<(), Error> {
+    KinSettings::check_recipient_names(&args.recipients)?;
+    for warning in validation::recipient_warnings(&args.recipients, args.threshold) {
+        println!("WARNING: {}. Add more with `kin recipient add`.", warning);
+    }
+
     let project = match &args.directory {
         Some(dir) => KinProject::init(&dir)?,
         None => KinProject::init(&std::env::current_dir()?)?,
@@ -24,8 +29,15 @@ pub fn run(args: &InitArgs) -> Result<(), Error> {
         })
         .collect();
 
-    let config = KinSettings::new(&owner, recipients);
-    config.write(&project.config_file())?;
+    let owner_passphrase = if args.no_owner_passphrase {
+        None
+    } else {
+        prompt_owner_passphrase()?
+    };
+    project.set_owner_passphrase(owner_passphrase);
+
+    let config = KinSettings::new(&owner, recipients, args.kdf_profile, args.threshold);
+    project.write_settings(&config)?;
 
     let overview_contents = include_bytes!("readme_templates/overview.md");
     let mut file = File::create(project.overview_readme_template())?;
@@ -49,65 +61,23 @@ fn prompt_owner_name() -> Result<String, Error> {
     }
 }
 
-fn random_passphrase() -> String {
-    let word_list = get_words();
-    random_passphrase_from(word_list)
-}
-
-fn random_passphrase_from(word_list: Vec<&str>) -> String {
-    let mut passphrase = String::new();
-    (0..10)
-        .map(|_| random_int() as usize)
-        .map(|r| r % word_list.len())
-        .map(|i| word_list[i])
-        .for_each(|word| {
-            passphrase.push_str(word);
-            passphrase.push(' ');
-        });
-
-    // Remove extra last space
-    passphrase.pop();
-
-    let passphrase = passphrase; // Get immutable value for returning
-    passphrase
-}
-
-fn random_int() -> u32 {
-    let buffer: &mut [u8; std::mem::size_of::<u32>()] = &mut [0; std::mem::size_of::<u32>()];
-    libsodium::randombytes_into(buffer);
-
-    let buffer = *buffer; // Get immutable array
-
-    unsafe { std::mem::transmute::<[u8; 4], u32>(buffer) }
-}
-
-fn get_words() -> Vec<&'static str> {
-    // Got this word list from:
-    // https://www.eff.org/deeplinks/2016/07/new-wordlists-random-passphrases
-
-    let raw_file = include_str!("eff_large_wordlist.txt");
-
-    raw_file.split_whitespace().collect()
-}
-
-#[cfg(test)]
-mod tests {
+fn prompt_owner_passphrase() -> Result<Option<SecretString>, Error> {
+    println!("Choose an owner passphrase to protect this project's settings, which include");
+    println!("the master key and every recipient's passphrase. Leave it blank to store them");
+    println!("unencrypted.");
 
-    #[test]
-    fn word_list() {
-        let words = super::get_words();
-
-        assert_eq!(words[0], "abacus");
-        assert_eq!(words[words.len() - 1], "zoom");
-
-        assert!(words.len() >= 7776, "Number of words has decreased. Either use a larger word list or consider increasing the number of words in passphrases to maintain a high entropy.");
-    }
+    loop {
+        let passphrase = ui::prompt_passphrase("Owner passphrase: ")?;
+        if passphrase.len() == 0 {
+            println!("WARNING: Project settings will be stored unencrypted.");
+            return Ok(None);
+        }
 
-    #[test]
-    fn random_passphrase_single_word() {
-        let words = vec!["foo"];
-        let passphrase = super::random_passphrase_from(words);
+        let confirmation = ui::prompt_passphrase("Confirm owner passphrase: ")?;
+        if passphrase == confirmation {
+            return Ok(Some(passphrase));
+        }
 
-        assert_eq!(passphrase, "foo foo foo foo foo foo foo foo foo foo");
+        println!("Passphrases don't match. Try again.");
     }
 }
@@ -1,20 +1,29 @@
 use kin_core;
 use kin_core::{CliResult, SubCommand};
+use std::ffi::OsStr;
 mod compile;
+mod doctor;
 mod init;
+mod passphrase;
+mod recipient;
+mod rekey;
+mod report;
+mod status;
+mod verify;
 
 fn main() -> CliResult {
     kin_core::libsodium_init()?;
 
     // TODO: Kill this and replace with new stand-alone decrypt executable
-    if std::env::args().len() <= 1 {
-        // Not run with any args; the first arg in the array is just the
-        // executable path
+    let list = kin_core::decrypt::list_requested();
+    if std::env::args_os().len() <= 1 || list {
+        // Not run with any args (other than --list); the first arg in the
+        // array is just the executable path
 
         let exe_path = std::env::current_exe()?; // Intentionally not getting the exe path from the first arg
-        let exe_name = exe_path.file_name().unwrap().to_str().unwrap();
+        let exe_name = exe_path.file_name();
 
-        if exe_name == "decrypt" {
+        if exe_name == Some(OsStr::new("decrypt")) {
             // This executable is being run from a previously-generated backup
             // package. It is being run by a backup holder that shouldn't be
             // bothered with pesky command line syntax.... It's ok, we already
@@ -23,6 +32,9 @@ fn main() -> CliResult {
             let decrypt_args = kin_core::DecryptArgs {
                 backup_dir: Some(exe_path.parent().unwrap().to_path_buf()),
                 destination: None, // Will prompt the user for a destination
+                extract: !list,
+                list: list,
+                only: Vec::new(),
             };
 
             kin_core::decrypt::run(&decrypt_args)?;
@@ -37,6 +49,11 @@ fn main() -> CliResult {
         SubCommand::Init(args) => init::run(&args),
         SubCommand::Compile(args) => compile::run(&args),
         SubCommand::Decrypt(args) => kin_core::decrypt::run(&args),
+        SubCommand::Verify(args) => verify::run(&args),
+        SubCommand::Recipient(args) => recipient::run(&args),
+        SubCommand::Rekey(args) => rekey::run(&args),
+        SubCommand::Status(args) => status::run(&args),
+        SubCommand::Doctor(args) => doctor::run(&args),
     }?;
 
     Ok(())
@@ -0,0 +1,160 @@
+use crate::passphrase::random_passphrase;
+use crate::status::print_reissue_steps;
+use kin_core::{bail, Error, IssuedPackage, KinProject, KinSettings};
+use kin_core::{
+    RecipientAddArgs, RecipientArgs, RecipientCommand, RecipientListArgs, RecipientRemoveArgs,
+    RecipientRenameArgs, RecipientRotateArgs,
+};
+use std::path::PathBuf;
+
+pub fn run(args: &RecipientArgs) -> Result<(), Error> {
+    match &args.cmd {
+        RecipientCommand::Add(args) => add(args),
+        RecipientCommand::Remove(args) => remove(args),
+        RecipientCommand::List(args) => list(args),
+        RecipientCommand::Rename(args) => rename(args),
+        RecipientCommand::Rotate(args) => rotate(args),
+    }
+}
+
+fn add(args: &RecipientAddArgs) -> Result<(), Error> {
+    let project = open_project(&args.project_dir)?;
+    let mut settings = read_settings(&project)?;
+
+    settings.add_recipient(&args.name, random_passphrase())?;
+    project.write_settings(&settings)?;
+
+    println!("Added {}.", args.name);
+    println!(
+        "Compile a backup for them with `kin compile --recipient \"{}\" <dest-dir>`.",
+        args.name
+    );
+    println!(
+        "Backups you've already given out don't know {}'s passphrase. Recompile them if",
+        args.name
+    );
+    println!("{} should be able to unlock them.", args.name);
+
+    Ok(())
+}
+
+fn remove(args: &RecipientRemoveArgs) -> Result<(), Error> {
+    let project = open_project(&args.project_dir)?;
+    let mut settings = read_settings(&project)?;
+    let name = &args.name;
+
+    settings.remove_recipient(name)?;
+
+    let mut registry = project.registry()?;
+    let stale = registry.mark_stale(|x| {
+        if x.peers.contains(name) {
+            Some(format!(
+                "still accepts {}'s passphrase, but {} was removed",
+                name, name
+            ))
+        } else {
+            None
+        }
+    });
+
+    // Recorded first, so a failure can't leave a removed holder's passphrase
+    // in packages nobody knows to reissue
+    project.write_registry(&registry)?;
+    project.write_settings(&settings)?;
+
+    println!(
+        "Removed {}. Get {}'s copy of the backup back if you can.",
+        name, name
+    );
+    print_reissue_steps(&settings, &registry, &stale_reasons(&stale));
+
+    Ok(())
+}
+
+fn list(args: &RecipientListArgs) -> Result<(), Error> {
+    let project = open_project(&args.project_dir)?;
+    let settings = read_settings(&project)?;
+
+    for recip in settings.recipients.iter() {
+        println!("{}", recip.name);
+    }
+
+    Ok(())
+}
+
+fn rename(args: &RecipientRenameArgs) -> Result<(), Error> {
+    let project = open_project(&args.project_dir)?;
+    let mut settings = read_settings(&project)?;
+
+    settings.rename_recipient(&args.name, &args.new_name)?;
+
+    let mut registry = project.registry()?;
+    registry.rename_recipient(&args.name, &args.new_name);
+
+    project.write_settings(&settings)?;
+    project.write_registry(&registry)?;
+
+    println!("Renamed {} to {}.", args.name, args.new_name);
+    println!(
+        "Their passphrase hasn't changed, but backups you've already compiled still call them {}.",
+        args.name
+    );
+
+    Ok(())
+}
+
+fn rotate(args: &RecipientRotateArgs) -> Result<(), Error> {
+    let project = open_project(&args.project_dir)?;
+    let mut settings = read_settings(&project)?;
+    let name = &args.name;
+
+    settings.set_passphrase(name, random_passphrase())?;
+
+    let mut registry = project.registry()?;
+    let stale = registry.mark_stale(|x| {
+        if &x.recipient == name {
+            Some(format!("shows {}'s old passphrase in its readme", name))
+        } else if x.peers.contains(name) {
+            Some(format!("has a key slot for {}'s old passphrase", name))
+        } else {
+            None
+        }
+    });
+
+    // Recorded first, so a failure can't leave the old passphrase in
+    // packages nobody knows to reissue
+    project.write_registry(&registry)?;
+    project.write_settings(&settings)?;
+
+    println!("Generated a new passphrase for {}.", name);
+    print_reissue_steps(&settings, &registry, &stale_reasons(&stale));
+    println!(
+        "Until they're reissued, {}'s old passphrase can still help unlock them.",
+        name
+    );
+
+    Ok(())
+}
+
+fn stale_reasons(stale: &Vec<IssuedPackage>) -> Vec<(String, String)> {
+    stale
+        .iter()
+        .map(|x| (x.recipient.clone(), x.stale.clone().unwrap()))
+        .collect()
+}
+
+fn open_project(dir: &Option<PathBuf>) -> Result<KinProject, Error> {
+    let project = match dir {
+        Some(dir) => KinProject::from(&dir),
+        None => KinProject::from(&std::env::current_dir()?),
+    };
+
+    Ok(project)
+}
+
+fn read_settings(project: &KinProject) -> Result<KinSettings, Error> {
+    match project.settings() {
+        Ok(settings) => Ok(settings),
+        Err(e) => bail!("unable to read settings: {}", e),
+    }
+}
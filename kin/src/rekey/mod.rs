@@ -0,0 +1,120 @@
+use crate::passphrase::random_passphrase;
+use crate::status::print_reissue_steps;
+use kin_core::{bail, Error, KinProject, KinSettings, RekeyArgs};
+
+pub fn run(args: &RekeyArgs) -> Result<(), Error> {
+    let project = match &args.project_dir {
+        Some(dir) => KinProject::from(&dir),
+        None => KinProject::from(&std::env::current_dir()?),
+    };
+
+    let mut settings = match project.settings() {
+        Ok(settings) => settings,
+        Err(e) => bail!("unable to read settings: {}", e),
+    };
+
+    let theft_advice = rekey(&mut settings, args.stolen.as_ref())?;
+    project.write_settings(&settings)?;
+
+    let generation = settings.master_key_generation();
+    println!(
+        "Generated master key {}. Backups you compile from now on use it.",
+        generation
+    );
+    println!("Backups compiled with an older key can still reveal what they were compiled");
+    println!("with, but nothing you add later.");
+    println!("{}", theft_advice);
+
+    let registry = project.registry()?;
+    let outdated = registry
+        .latest()
+        .iter()
+        .filter(|x| x.key_generation < generation)
+        .map(|x| {
+            (
+                x.recipient.clone(),
+                format!("uses retired master key {}", x.key_generation),
+            )
+        })
+        .collect();
+    print_reissue_steps(&settings, &registry, &outdated);
+
+    Ok(())
+}
+
+/// Replaces the master key and, if a backup was stolen, its holder's
+/// passphrase, which the stolen readme shows. Returns what to tell the owner
+/// about that passphrase.
+fn rekey(settings: &mut KinSettings, stolen: Option<&String>) -> Result<String, Error> {
+    let advice = match stolen {
+        Some(name) => {
+            settings.set_passphrase(name, random_passphrase())?;
+            format!(
+                "Generated a new passphrase for {}, since the stolen backup's readme\nshows the old one.",
+                name
+            )
+        }
+        None => String::from(
+            "If a backup was stolen, its readme shows its holder's passphrase, which still\n\
+             unlocks every other backup that passphrase has a key slot in. Before you\n\
+             reissue anything, give them a new one with `kin recipient rotate <holder>`.",
+        ),
+    };
+
+    settings.rekey()?;
+    Ok(advice)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kin_core::test_support::settings_named;
+
+    #[test]
+    fn rekey_rotates_the_stolen_backups_passphrase() {
+        let mut settings = settings_named(&["Alice", "Bob", "Chuck"], 1);
+        let key = settings.master_key().unwrap();
+
+        let advice = rekey(&mut settings, Some(&String::from("Bob"))).unwrap();
+        assert!(advice.contains("new passphrase for Bob"));
+        assert!(settings.master_key().unwrap() != key);
+
+        let passphrase = |name: &str| {
+            settings
+                .get_recipient(&String::from(name))
+                .unwrap()
+                .passphrase
+                .as_str()
+                .to_string()
+        };
+        assert_eq!(passphrase("Alice"), "Alice");
+        assert_ne!(passphrase("Bob"), "Bob");
+        assert_eq!(passphrase("Chuck"), "Chuck");
+    }
+
+    #[test]
+    fn rekey_says_to_rotate_when_it_doesnt_know_whats_stolen() {
+        let mut settings = settings_named(&["Alice", "Bob"], 1);
+
+        let advice = rekey(&mut settings, None).unwrap();
+        assert!(advice.contains("kin recipient rotate <holder>"));
+        assert_eq!(settings.master_key_generation(), 2);
+        assert_eq!(
+            settings
+                .get_recipient(&String::from("Bob"))
+                .unwrap()
+                .passphrase
+                .as_str(),
+            "Bob"
+        );
+    }
+
+    #[test]
+    fn rekey_changes_nothing_for_an_unknown_holder() {
+        let mut settings = settings_named(&["Alice", "Bob"], 1);
+
+        assert!(rekey(&mut settings, Some(&String::from("Dana"))).is_err());
+        assert_eq!(settings.master_key_generation(), 1);
+    }
+}
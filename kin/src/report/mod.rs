@@ -0,0 +1,54 @@
+use kin_core::{bail, Error};
+
+/// Collects problems as they're found, so one bad check doesn't hide the rest
+pub struct Report {
+    problems: usize,
+
+    /// What's being checked, e.g. "backup"
+    subject: &'static str,
+
+    /// Printed by `finish` when nothing was wrong
+    all_clear: &'static str,
+}
+
+impl Report {
+    pub fn new(subject: &'static str, all_clear: &'static str) -> Report {
+        Report {
+            problems: 0,
+            subject: subject,
+            all_clear: all_clear,
+        }
+    }
+
+    pub fn ok(&self, message: &str) {
+        println!("  ok: {}", message);
+    }
+
+    pub fn problem(&mut self, message: &str) {
+        println!("  PROBLEM: {}", message);
+        self.problems += 1;
+    }
+
+    /// Reports a problem along with what the owner can do about it
+    pub fn problem_with_fix(&mut self, message: &str, fix: &str) {
+        self.problem(message);
+        println!("    fix: {}", fix);
+    }
+
+    pub fn problems(&self) -> usize {
+        self.problems
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.problems > 0 {
+            bail!(
+                "found {} problem(s) with this {}",
+                self.problems,
+                self.subject
+            );
+        }
+
+        println!("{}", self.all_clear);
+        Ok(())
+    }
+}
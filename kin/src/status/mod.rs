@@ -0,0 +1,242 @@
+use kin_core::{bail, Error, IssuedPackage, KinProject, KinSettings, PackageRegistry, StatusArgs};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn run(args: &StatusArgs) -> Result<(), Error> {
+    let project = match &args.project_dir {
+        Some(dir) => KinProject::from(&dir),
+        None => KinProject::from(&std::env::current_dir()?),
+    };
+
+    let settings = match project.settings() {
+        Ok(settings) => settings,
+        Err(e) => bail!("unable to read settings: {}", e),
+    };
+
+    let registry = project.registry()?;
+    let content_hash = project.content_hash()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    println!("Master key: {}", settings.master_key_generation());
+    if !settings.retired_master_keys().is_empty() {
+        let retired: Vec<String> = settings
+            .retired_master_keys()
+            .iter()
+            .map(|x| x.generation.to_string())
+            .collect();
+        println!("Retired master keys: {}", retired.join(", "));
+    }
+    println!();
+
+    let mut counts = [0; 3];
+    let mut on_retired_key = 0;
+    for recip in settings.recipients.iter() {
+        match registry.latest_for(&recip.name) {
+            Some(package) => {
+                let state = assess(&settings, package, &content_hash);
+                print_package(package, &state, now);
+                counts[state.index()] += 1;
+
+                if package.key_generation < settings.master_key_generation() {
+                    on_retired_key += 1;
+                }
+            }
+            None => println!("{}: no backup compiled yet", recip.name),
+        }
+    }
+
+    // Backups still held by people who were removed
+    for package in registry.latest().iter() {
+        if settings.get_recipient(&package.recipient).is_err() {
+            let state = assess(&settings, package, &content_hash);
+            print_package(package, &state, now);
+            counts[state.index()] += 1;
+        }
+    }
+
+    println!();
+    println!(
+        "{} current, {} stale, {} revoked",
+        counts[0], counts[1], counts[2]
+    );
+    if on_retired_key > 0 {
+        println!(
+            "{} backup(s) still use a retired master key.",
+            on_retired_key
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PackageState {
+    Current,
+
+    /// Should be reissued to keep its holder up to date
+    Stale(Vec<String>),
+
+    /// Shouldn't be out there at all anymore
+    Revoked(Vec<String>),
+}
+
+impl PackageState {
+    fn index(&self) -> usize {
+        match self {
+            PackageState::Current => 0,
+            PackageState::Stale(_) => 1,
+            PackageState::Revoked(_) => 2,
+        }
+    }
+}
+
+/// Compares a package with the project as it is now. `content_hash` is the
+/// project's current `KinProject::content_hash`.
+pub fn assess(settings: &KinSettings, package: &IssuedPackage, content_hash: &str) -> PackageState {
+    let mut revoked = Vec::new();
+
+    if settings.get_recipient(&package.recipient).is_err() {
+        revoked.push(String::from(
+            "belongs to someone who is no longer a backup holder",
+        ));
+    }
+
+    if package.key_generation < settings.master_key_generation() {
+        revoked.push(format!(
+            "uses retired master key {}",
+            package.key_generation
+        ));
+    }
+
+    if !revoked.is_empty() {
+        return PackageState::Revoked(revoked);
+    }
+
+    let mut stale = Vec::new();
+
+    if let Some(reason) = &package.stale {
+        stale.push(reason.clone());
+    }
+
+    if !package.content_hash.is_empty() && package.content_hash != content_hash {
+        stale.push(String::from(
+            "was compiled before the latest changes to the project's files or readme templates",
+        ));
+    }
+
+    if let Ok(peers) = settings.get_peers(&package.recipient) {
+        for peer in peers.iter().filter(|x| !package.peers.contains(&x.name)) {
+            stale.push(format!("has no key slot for {}'s passphrase", peer.name));
+        }
+    }
+
+    if stale.is_empty() {
+        PackageState::Current
+    } else {
+        PackageState::Stale(stale)
+    }
+}
+
+fn print_package(package: &IssuedPackage, state: &PackageState, now: u64) {
+    let (label, reasons) = match state {
+        PackageState::Current => ("current", None),
+        PackageState::Stale(reasons) => ("stale", Some(reasons)),
+        PackageState::Revoked(reasons) => ("revoked", Some(reasons)),
+    };
+
+    println!(
+        "{}: {} (compiled {})",
+        package.recipient,
+        label,
+        describe_age(package.created, now)
+    );
+
+    for reason in reasons.into_iter().flatten() {
+        println!("  - it {}", reason);
+    }
+}
+
+/// Walks the owner through replacing backups that have already been given
+/// out. `stale` pairs recipients with the reason their backup is stale.
+pub fn print_reissue_steps(
+    settings: &KinSettings,
+    registry: &PackageRegistry,
+    stale: &Vec<(String, String)>,
+) {
+    let stale: Vec<&(String, String)> = stale
+        .iter()
+        .filter(|(name, _)| settings.get_recipient(name).is_ok())
+        .collect();
+
+    let recipients: Vec<&String> = if registry.latest().is_empty() {
+        println!("Kin has no record of which backups you've compiled, so reissue all of them.");
+        settings.recipients.iter().map(|x| &x.name).collect()
+    } else if stale.is_empty() {
+        return;
+    } else {
+        println!("These backups need to be reissued:");
+        for (name, reason) in stale.iter() {
+            println!("  {}'s: it {}", name, reason);
+        }
+        stale.iter().map(|(name, _)| name).collect()
+    };
+
+    println!("For each one, compile a new package and swap it for the old one:");
+    for name in recipients {
+        println!("  kin compile --recipient \"{}\" <dest-dir>", name);
+    }
+}
+
+fn describe_age(created: u64, now: u64) -> String {
+    match now.saturating_sub(created) / (60 * 60 * 24) {
+        0 => String::from("today"),
+        1 => String::from("yesterday"),
+        days => format!("{} days ago", days),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kin_core::test_support::{issued_package, settings_named};
+
+    fn package(recipient: &str, peers: &[&str]) -> IssuedPackage {
+        IssuedPackage {
+            content_hash: String::from("1234"),
+            ..issued_package("AAAA", recipient, peers)
+        }
+    }
+
+    #[test]
+    fn assess_packages() {
+        let mut settings = settings_named(&["Alice", "Bob", "Chuck"], 1);
+        let alice = package("Alice", &["Bob", "Chuck"]);
+
+        assert_eq!(assess(&settings, &alice, "1234"), PackageState::Current);
+        assert_eq!(assess(&settings, &alice, "5678").index(), 1);
+        assert_eq!(
+            assess(&settings, &package("Alice", &["Bob"]), "1234"),
+            PackageState::Stale(vec![String::from("has no key slot for Chuck's passphrase")])
+        );
+        assert_eq!(
+            assess(&settings, &package("Eve", &["Alice"]), "1234").index(),
+            2
+        );
+
+        settings.rekey().unwrap();
+        assert_eq!(
+            assess(&settings, &alice, "1234"),
+            PackageState::Revoked(vec![String::from("uses retired master key 1")])
+        );
+    }
+
+    #[test]
+    fn describe_age() {
+        let day = 60 * 60 * 24;
+
+        assert_eq!(super::describe_age(1000, 1000 + day - 1), "today");
+        assert_eq!(super::describe_age(1000, 1000 + day), "yesterday");
+        assert_eq!(super::describe_age(1000, 1000 + 30 * day), "30 days ago");
+        assert_eq!(super::describe_age(1000, 0), "today");
+    }
+}
@@ -0,0 +1,349 @@
+use crate::report::Report;
+use kin_core::decrypt;
+use kin_core::libsodium;
+use kin_core::libsodium::{Decryptor, KeyShare, MasterKey};
+use kin_core::{bail, BackupPackage, Error, KinProject, KinSettings, Manifest, VerifyArgs};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+
+pub fn run(args: &VerifyArgs) -> Result<(), Error> {
+    let project = match &args.project_dir {
+        Some(dir) => KinProject::from(&dir),
+        None => KinProject::from(&std::env::current_dir()?),
+    };
+
+    let settings = match project.settings() {
+        Ok(settings) => settings,
+        Err(e) => bail!("unable to read settings: {}", e),
+    };
+
+    let package = BackupPackage::from(&args.package_dir);
+    if !package.config_file_path().exists() {
+        bail!("{} is not a Kin backup package", args.package_dir.display());
+    }
+
+    let recipient = match &args.recipient {
+        Some(name) => name.clone(),
+        None => match project.registry()?.compiled_for(&package)? {
+            Some(name) => name,
+            None => bail!("this backup doesn't record who it's for; use --recipient"),
+        },
+    };
+
+    let mut report = Report::new("backup", "This backup is intact and up to date.");
+
+    println!("Checking files...");
+    check_files(&package, &settings, &mut report)?;
+    check_registry(&project, &package, &mut report)?;
+
+    println!("Checking passphrases (this may take a while)...");
+    check_passphrases(&package, &settings, &recipient, &mut report)?;
+
+    println!("Checking private content...");
+    check_private_content(&project, &package, &settings, &mut report)?;
+
+    report.finish()
+}
+
+/// The owner may already know this package needs to be reissued, e.g. after
+/// a passphrase was rotated
+fn check_registry(
+    project: &KinProject,
+    package: &BackupPackage,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let registry = project.registry()?;
+    if let Some(issued) = registry.get(&package.package_id()?) {
+        if let Some(reason) = &issued.stale {
+            report.problem(&format!("this backup needs to be reissued: it {}", reason));
+        }
+    }
+
+    Ok(())
+}
+
+/// Every file should be exactly what `compile` produced, signed with this
+/// project's key.
+fn check_files(
+    package: &BackupPackage,
+    settings: &KinSettings,
+    report: &mut Report,
+) -> Result<(), Error> {
+    if !package.manifest_path().exists() {
+        if package.should_be_signed()? {
+            report.problem("the backup's signed manifest is missing");
+        } else {
+            report.problem("the backup isn't signed (it was compiled by an older version of Kin)");
+        }
+        return Ok(());
+    }
+
+    let manifest = match Manifest::read_verified(package) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.problem(&e.to_string());
+            return Ok(());
+        }
+    };
+
+    let package_key = manifest.public_key()?;
+    match settings.signing_key()? {
+        Some(key) if key.public_key() == package_key => {
+            report.ok("signed with this project's key");
+        }
+        Some(key) => report.problem(&format!(
+            "signed with key {}, but this project's key is {}",
+            package_key.fingerprint(),
+            key.public_key().fingerprint()
+        )),
+        None => report.problem("this project has no signing key to check the signature against"),
+    }
+
+    let changed = manifest.changed_files(package)?;
+    for path in changed.iter() {
+        report.problem(&format!("{} has changed since it was compiled", path));
+    }
+
+    let unlisted = manifest.unlisted_files(package)?;
+    for path in unlisted.iter() {
+        report.problem(&format!("{} wasn't part of the compiled backup", path));
+    }
+
+    if changed.is_empty() && unlisted.is_empty() {
+        report.ok(&format!(
+            "all {} files match what was compiled",
+            manifest.files.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Every peer's passphrase should unlock the project's current master key,
+/// and the recipient's own passphrase shouldn't unlock anything.
+fn check_passphrases(
+    package: &BackupPackage,
+    settings: &KinSettings,
+    recipient: &String,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let master_key = settings.master_key()?;
+    let threshold = package.threshold()?;
+    let own_passphrase = &settings.get_recipient(recipient)?.passphrase;
+
+    if threshold <= 1 {
+        for peer in settings.get_peers(recipient)? {
+            match package.decrypt_master_key(&peer.passphrase) {
+                Ok(ref key) if key == &master_key => {
+                    report.ok(&format!("{}'s passphrase unlocks it", peer.name))
+                }
+                Ok(key) => report.problem(&format!(
+                    "{}'s passphrase unlocks {}, not the project's current one",
+                    peer.name,
+                    describe_old_key(settings, &key)?
+                )),
+                Err(_) => report.problem(&format!("{}'s passphrase doesn't unlock it", peer.name)),
+            }
+        }
+
+        if package.decrypt_master_key(own_passphrase).is_ok() {
+            report.problem(&format!("{}'s own passphrase unlocks it", recipient));
+        }
+
+        return Ok(());
+    }
+
+    let mut shares: Vec<KeyShare> = Vec::new();
+    for peer in settings.get_peers(recipient)? {
+        match package.decrypt_key_share(&peer.passphrase)? {
+            Some(share) => {
+                report.ok(&format!("{}'s passphrase unlocks a key share", peer.name));
+                shares.push(share);
+            }
+            None => report.problem(&format!(
+                "{}'s passphrase doesn't unlock a key share",
+                peer.name
+            )),
+        }
+    }
+
+    if shares.len() < threshold as usize {
+        report.problem(&format!(
+            "only {} passphrases unlock key shares, but {} are needed",
+            shares.len(),
+            threshold
+        ));
+    } else {
+        shares.truncate(threshold as usize);
+        match libsodium::MasterKey::from_shares(&shares) {
+            Ok(ref key) if key == &master_key => {
+                report.ok(&format!("any {} of them rebuild the master key", threshold))
+            }
+            Ok(key) => report.problem(&format!(
+                "the key shares rebuild {}, not the project's current one",
+                describe_old_key(settings, &key)?
+            )),
+            Err(_) => report.problem("the key shares don't rebuild a master key"),
+        }
+    }
+
+    if package.decrypt_key_share(own_passphrase)?.is_some() {
+        report.problem(&format!(
+            "{}'s own passphrase unlocks a key share",
+            recipient
+        ));
+    }
+
+    Ok(())
+}
+
+fn describe_old_key(settings: &KinSettings, key: &MasterKey) -> Result<String, Error> {
+    Ok(match settings.master_key_generation_of(key)? {
+        Some(generation) => format!("retired master key {}", generation),
+        None => String::from("a master key this project doesn't have"),
+    })
+}
+
+/// private.kin should decrypt completely, and hold exactly what's in the
+/// project's private folder right now.
+fn check_private_content(
+    project: &KinProject,
+    package: &BackupPackage,
+    settings: &KinSettings,
+    report: &mut Report,
+) -> Result<(), Error> {
+    // Packages compiled before a rekey still open with the key they were
+    // compiled with; check_passphrases already reports that
+    let mut packaged = hash_private_archive(package, &settings.master_key()?);
+    for retired in settings.retired_master_keys().iter() {
+        if packaged.is_ok() {
+            break;
+        }
+        packaged = hash_private_archive(package, &retired.master_key()?);
+    }
+
+    let packaged = match packaged {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            report.problem(&format!("unable to decrypt private.kin: {}", e));
+            return Ok(());
+        }
+    };
+
+    let mut current = BTreeMap::new();
+    for file in project.private_files()?.iter().filter(|x| !x.is_dir) {
+        let mut contents = File::open(&file.path)?;
+        current.insert(
+            file.name.clone(),
+            libsodium::to_hex(&libsodium::sha256_reader(&mut contents)?),
+        );
+    }
+
+    let mut drifted = false;
+    for (path, hash) in current.iter() {
+        match packaged.get(path) {
+            Some(packaged_hash) if packaged_hash == hash => {}
+            Some(_) => {
+                report.problem(&format!(
+                    "{} has changed since the backup was compiled",
+                    path
+                ));
+                drifted = true;
+            }
+            None => {
+                report.problem(&format!("{} was added since the backup was compiled", path));
+                drifted = true;
+            }
+        }
+    }
+
+    for path in packaged.keys().filter(|path| !current.contains_key(*path)) {
+        report.problem(&format!(
+            "{} was removed since the backup was compiled",
+            path
+        ));
+        drifted = true;
+    }
+
+    if !drifted {
+        report.ok(&format!(
+            "private.kin decrypts, and matches all {} private files",
+            current.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decrypts private.kin as it's read, hashing every file in it. Nothing is
+/// written to disk.
+fn hash_private_archive(
+    package: &BackupPackage,
+    key: &MasterKey,
+) -> Result<BTreeMap<String, String>, Error> {
+    let input = BufReader::new(File::open(package.private_archive_path())?);
+    let associated_data = package.associated_data()?;
+    let mut decryptor = Decryptor::new(key, &associated_data, input)?;
+
+    let mut hashes = BTreeMap::new();
+    decrypt::read_entries(&mut decryptor, |file| {
+        if file.name().ends_with('/') {
+            return Ok(()); // Directory
+        }
+
+        let name = file.name().to_string();
+        hashes.insert(name, libsodium::to_hex(&libsodium::sha256_reader(file)?));
+        Ok(())
+    })?;
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kin_core::libsodium::SigningKey;
+    use kin_core::test_support::TempDir;
+    use kin_core::ArchiveBinding;
+    use std::fs;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn damaged_private_archive() {
+        let temp = TempDir::new();
+        let package = BackupPackage::init(
+            &temp.path().join("package"),
+            &String::from("Alice"),
+            Vec::new(),
+            1,
+            &ArchiveBinding::new(&String::from("Owen"), &SigningKey::new().public_key()).unwrap(),
+        )
+        .unwrap();
+
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        archive.start_file("big.bin", options).unwrap();
+        archive.write_all(&[7; 100000]).unwrap();
+        let zipped = archive.finish().unwrap().into_inner();
+
+        let key = MasterKey::new();
+        let mut encrypted = Vec::new();
+        libsodium::encrypt(
+            &key,
+            &package.associated_data().unwrap(),
+            &mut &zipped[..],
+            &mut encrypted,
+        )
+        .unwrap();
+
+        let path = package.private_archive_path();
+        fs::write(&path, &encrypted).unwrap();
+        assert_eq!(hash_private_archive(&package, &key).unwrap().len(), 1);
+
+        fs::write(&path, &encrypted[0..encrypted.len() / 2]).unwrap();
+        assert!(hash_private_archive(&package, &key).is_err());
+    }
+}
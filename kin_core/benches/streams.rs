@@ -0,0 +1,121 @@
+//! Throughput of private archive encryption and decryption.
+//!
+//! Run with `cargo bench -p kin_core`. Set KIN_BENCH_MIB to change how much
+//! data is pushed through each case (default: 64 MiB).
+
+use kin_core::libsodium;
+use kin_core::libsodium::MasterKey;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MIB: usize = 1024 * 1024;
+
+/// The byte-at-a-time case is slow enough that it gets less data
+const BYTEWISE_MAX_MIB: usize = 8;
+
+/// Drives a reader the way `streams` used to before reads were done in
+/// blocks: through `Read::bytes()`, which makes one `read` call per byte.
+struct OneByteAtATime<R: Read>(R);
+
+impl<R: Read> Read for OneByteAtATime<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[0..len])
+    }
+}
+
+fn main() {
+    libsodium::init().expect("unable to initialize libsodium");
+
+    let mib = match std::env::var("KIN_BENCH_MIB") {
+        Ok(value) => value.parse().expect("KIN_BENCH_MIB must be a number"),
+        Err(_) => 64,
+    };
+
+    let key = MasterKey::new();
+    let plaintext_path = temp_path("plaintext");
+    let small_plaintext_path = temp_path("plaintext-small");
+    let ciphertext_path = temp_path("ciphertext");
+    write_test_file(&plaintext_path, mib);
+    write_test_file(&small_plaintext_path, mib.min(BYTEWISE_MAX_MIB));
+
+    println!("encrypting from a file:");
+
+    let bytewise = bench(
+        "byte-at-a-time reads (previous implementation)",
+        mib.min(BYTEWISE_MAX_MIB),
+        || {
+            let mut input = OneByteAtATime(File::open(&small_plaintext_path).unwrap());
+            libsodium::encrypt(&key, &[], &mut input, &mut std::io::sink()).unwrap();
+        },
+    );
+
+    let mut block = Vec::new();
+    for chunk_size in [16 * 1024, 64 * 1024, MIB].iter() {
+        let name = format!("block reads, {} KiB chunks", chunk_size / 1024);
+        block.push(bench(&name, mib, || {
+            let mut input = File::open(&plaintext_path).unwrap();
+            libsodium::encrypt_with_chunk_size(
+                &key,
+                &[],
+                *chunk_size as u32,
+                &mut input,
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        }));
+    }
+
+    println!(
+        "block reads are {:.0}x faster than byte-at-a-time reads",
+        block[0] / bytewise
+    );
+
+    println!("decrypting from a file:");
+
+    {
+        let mut input = File::open(&plaintext_path).unwrap();
+        let mut output = BufWriter::new(File::create(&ciphertext_path).unwrap());
+        libsodium::encrypt(&key, &[], &mut input, &mut output).unwrap();
+    }
+
+    bench("block reads, 16 KiB chunks", mib, || {
+        let mut input = File::open(&ciphertext_path).unwrap();
+        libsodium::decrypt(&key, &[], &mut input, &mut std::io::sink()).unwrap();
+    });
+
+    for path in [plaintext_path, small_plaintext_path, ciphertext_path].iter() {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+/// Runs `f` once and prints its throughput in MiB/s, which is also returned
+fn bench<F: FnMut()>(name: &str, mib: usize, mut f: F) -> f64 {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+
+    let throughput = mib as f64 / seconds(elapsed);
+    println!("  {:<48} {:>10.1} MiB/s", name, throughput);
+    throughput
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("kin-bench-{}-{}", std::process::id(), name))
+}
+
+fn write_test_file(path: &PathBuf, mib: usize) {
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    let mut block = vec![0; MIB];
+    for _ in 0..mib {
+        libsodium::randombytes_into(&mut block);
+        file.write_all(&block).unwrap();
+    }
+    file.flush().unwrap();
+}
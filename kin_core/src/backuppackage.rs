@@ -1,5 +1,8 @@
 use super::fsutil;
-use super::libsodium::{EncryptedMasterKey, MasterKey};
+use super::libsodium;
+use super::libsodium::{
+    EncryptedMasterKey, KdfParams, KdfProfile, KeyShare, MasterKey, PublicKey, SecretString,
+};
 use super::Error;
 use failure::bail;
 use serde::{Deserialize, Serialize};
@@ -7,6 +10,7 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::iter::Iterator;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct BackupPackage {
     path: PathBuf,
@@ -21,7 +25,10 @@ impl BackupPackage {
 
     pub fn init(
         path: &PathBuf,
+        recipient: &String,
         encrypted_keys: Vec<EncryptedMasterKey>,
+        threshold: u8,
+        binding: &ArchiveBinding,
     ) -> Result<BackupPackage, Error> {
         fsutil::ensure_empty_dir(path)?;
         let package = BackupPackage::from(path);
@@ -33,19 +40,33 @@ impl BackupPackage {
                 data: x.data(),
                 passphrase_salt: x.passphrase_salt(),
                 nonce: x.nonce(),
+                kdf_algorithm: x.kdf().algorithm(),
+                kdf_opslimit: x.kdf().opslimit(),
+                kdf_memlimit: x.kdf().memlimit(),
             })
             .collect();
 
         let settings = PackageSettings {
             encrypted_keys: keys,
+            threshold: threshold,
+            package_id: random_id(),
+            archive_id: binding.id.clone(),
+            owner: binding.owner.clone(),
+            owner_public_key: binding.owner_public_key.clone(),
+            recipient: recipient.clone(),
+            created: binding.created,
         };
         settings.write(&package.config_file_path())?;
 
-        set_readonly(&package.config_file_path())?;
+        fsutil::set_readonly(&package.config_file_path())?;
 
         Ok(package)
     }
 
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
     pub fn config_dir_path(&self) -> PathBuf {
         self.path.join(".kin")
     }
@@ -63,6 +84,16 @@ impl BackupPackage {
         self.config_dir_path().join("private.kin")
     }
 
+    /// Lists hashes of everything else in the package
+    pub fn manifest_path(&self) -> PathBuf {
+        self.config_dir_path().join("manifest.json")
+    }
+
+    /// The owner's signature of the manifest
+    pub fn manifest_signature_path(&self) -> PathBuf {
+        self.config_dir_path().join("manifest.sig")
+    }
+
     /// The "private" directory containing all the decryption exes for the various operating systems
     pub fn decrypt_exe_dir(&self) -> PathBuf {
         self.path.join("private")
@@ -76,36 +107,172 @@ impl BackupPackage {
         self.decrypt_exe_dir().join("readme.html")
     }
 
-    pub fn decrypt_master_key(&self, passphrase: &String) -> Result<MasterKey, Error> {
-        let settings = match PackageSettings::read(&self.config_file_path()) {
-            Ok(settings) => settings,
+    /// How many passphrases it takes to unlock this package
+    pub fn threshold(&self) -> Result<u8, Error> {
+        Ok(self.settings()?.threshold)
+    }
+
+    pub fn decrypt_master_key(&self, passphrase: &SecretString) -> Result<MasterKey, Error> {
+        EncryptedMasterKey::decrypt_any(&self.encrypted_keys()?, passphrase)
+    }
+
+    /// For packages that need several passphrases: unlocks the share of the
+    /// master key that belongs to this passphrase, or `None` if it doesn't
+    /// unlock any of them.
+    pub fn decrypt_key_share(&self, passphrase: &SecretString) -> Result<Option<KeyShare>, Error> {
+        EncryptedMasterKey::decrypt_share_any(&self.encrypted_keys()?, passphrase)
+    }
+
+    /// Identifies this package. Empty for packages compiled before this was
+    /// recorded.
+    pub fn package_id(&self) -> Result<String, Error> {
+        Ok(self.settings()?.package_id)
+    }
+
+    /// When the package was compiled, in seconds since the Unix epoch
+    pub fn created(&self) -> Result<u64, Error> {
+        Ok(self.settings()?.created)
+    }
+
+    /// The owner's signing key, as recorded when the package was compiled.
+    /// private.kin is bound to it, so a manifest signed with any other key
+    /// can't have come from the owner.
+    pub fn owner_public_key(&self) -> Result<Option<PublicKey>, Error> {
+        let key = self.settings()?.owner_public_key;
+        if key.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(PublicKey::decode_base64(&key)?))
+        }
+    }
+
+    /// Whether the package must have a signed manifest. Every package
+    /// compiled since Kin started recording package IDs has one, so if it's
+    /// missing, someone removed it.
+    pub fn should_be_signed(&self) -> Result<bool, Error> {
+        Ok(self.settings()?.should_be_signed())
+    }
+
+    /// Who the package was compiled for, if it was recorded
+    pub fn recipient(&self) -> Result<Option<String>, Error> {
+        let recipient = self.settings()?.recipient;
+        if recipient.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(recipient))
+        }
+    }
+
+    /// The package metadata that private.kin is bound to. It must be passed to
+    /// both `libsodium::encrypt` and `libsodium::decrypt`.
+    pub fn associated_data(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.settings()?.associated_data())
+    }
+
+    fn encrypted_keys(&self) -> Result<Vec<EncryptedMasterKey>, Error> {
+        self.settings()?
+            .encrypted_keys
+            .iter()
+            .map(|x| x.to_encrypted_master_key())
+            .collect()
+    }
+
+    fn settings(&self) -> Result<PackageSettings, Error> {
+        match PackageSettings::read(&self.config_file_path()) {
+            Ok(settings) => Ok(settings),
             Err(err) => bail!(
                 "Unable to parse {}: {}",
-                self.config_file_path().to_str().unwrap(),
+                self.config_file_path().display(),
                 err
             ),
-        };
+        }
+    }
+}
 
-        let encrypted_keys: Vec<EncryptedMasterKey> = settings
-            .encrypted_keys
-            .iter()
-            .map(|x| EncryptedMasterKey::new(&x.data, &x.passphrase_salt, &x.nonce).unwrap())
-            .collect();
+/// What a package's private.kin is bound to. Packages compiled together
+/// share one, so they can share the same private.kin.
+pub struct ArchiveBinding {
+    id: String,
+    owner: String,
+    /// Base64. Empty for packages compiled before it was bound.
+    owner_public_key: String,
+    /// Seconds since the Unix epoch
+    created: u64,
+}
+
+impl ArchiveBinding {
+    pub fn new(owner: &String, owner_public_key: &PublicKey) -> Result<ArchiveBinding, Error> {
+        Ok(ArchiveBinding {
+            id: random_id(),
+            owner: owner.clone(),
+            owner_public_key: owner_public_key.encode_base64(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        let created = self.created.to_string();
+        let mut fields = vec![
+            self.id.as_bytes(),
+            self.owner.as_bytes(),
+            created.as_bytes(),
+        ];
 
-        for encr_key in encrypted_keys {
-            match encr_key.decrypt(passphrase) {
-                Ok(key) => return Ok(key),
-                Err(_) => continue, // Expected; check the next key in the collection
-            };
+        // Binding the signing key means a package re-signed with some other
+        // key won't decrypt, even if everything else about it checks out
+        if !self.owner_public_key.is_empty() {
+            fields.push(self.owner_public_key.as_bytes());
         }
 
-        bail!("Unable to decrypt master key"); // TODO: Be more specific
+        // Length-prefix each field so no two different packages can produce
+        // the same bytes
+        let mut data = b"kin package".to_vec();
+        for field in fields.iter() {
+            data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            data.extend_from_slice(field);
+        }
+
+        data
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PackageSettings {
     encrypted_keys: Vec<EncryptedKey>,
+    #[serde(default = "default_threshold")]
+    threshold: u8,
+
+    // Packages compiled before these were recorded have an empty package ID,
+    // and their private.kin isn't bound to anything.
+    #[serde(default)]
+    package_id: String,
+    /// Identifies private.kin. Packages compiled together share it. Packages
+    /// compiled before then don't have one, and their private.kin is bound
+    /// to the package ID instead.
+    #[serde(default)]
+    archive_id: String,
+    #[serde(default)]
+    owner: String,
+    /// The key the owner signs the package's manifest with, in base64. Empty
+    /// for packages compiled before private.kin was bound to it.
+    #[serde(default)]
+    owner_public_key: String,
+    /// Seconds since the Unix epoch
+    #[serde(default)]
+    created: u64,
+
+    /// Who the package was compiled for. Empty for packages compiled before
+    /// this was recorded.
+    #[serde(default)]
+    recipient: String,
+}
+
+const PACKAGE_ID_SIZE: usize = 16;
+
+fn random_id() -> String {
+    let mut id: [u8; PACKAGE_ID_SIZE] = [0; PACKAGE_ID_SIZE];
+    libsodium::randombytes_into(&mut id);
+    base64::encode(&id)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -113,9 +280,65 @@ struct EncryptedKey {
     data: String,
     passphrase_salt: String,
     nonce: String,
+
+    // Packages compiled before these were recorded always used the
+    // "sensitive" profile.
+    #[serde(default = "default_kdf_algorithm")]
+    kdf_algorithm: String,
+    #[serde(default = "default_kdf_opslimit")]
+    kdf_opslimit: u64,
+    #[serde(default = "default_kdf_memlimit")]
+    kdf_memlimit: u64,
+}
+
+impl EncryptedKey {
+    fn to_encrypted_master_key(&self) -> Result<EncryptedMasterKey, Error> {
+        let kdf = KdfParams::new(&self.kdf_algorithm, self.kdf_opslimit, self.kdf_memlimit)?;
+        EncryptedMasterKey::new(&self.data, &self.passphrase_salt, &self.nonce, kdf)
+    }
+}
+
+fn default_threshold() -> u8 {
+    1
+}
+
+fn default_kdf_algorithm() -> String {
+    KdfProfile::Sensitive.params().algorithm()
+}
+
+fn default_kdf_opslimit() -> u64 {
+    KdfProfile::Sensitive.params().opslimit()
+}
+
+fn default_kdf_memlimit() -> u64 {
+    KdfProfile::Sensitive.params().memlimit()
 }
 
 impl PackageSettings {
+    fn should_be_signed(&self) -> bool {
+        !self.package_id.is_empty() || self.created != 0
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        if self.package_id.is_empty() {
+            return Vec::new();
+        }
+
+        let id = if self.archive_id.is_empty() {
+            &self.package_id
+        } else {
+            &self.archive_id
+        };
+
+        ArchiveBinding {
+            id: id.clone(),
+            owner: self.owner.clone(),
+            owner_public_key: self.owner_public_key.clone(),
+            created: self.created,
+        }
+        .associated_data()
+    }
+
     pub fn write(&self, path: &PathBuf) -> Result<(), Error> {
         let config_serialized = serde_json::to_string_pretty(self)?;
 
@@ -134,19 +357,60 @@ impl PackageSettings {
     }
 }
 
-#[cfg(target_os = "linux")]
-use std::os::unix::fs::PermissionsExt;
+#[cfg(test)]
+mod tests {
 
-#[cfg(target_os = "linux")]
-fn set_readonly(path: &PathBuf) -> Result<(), Error> {
-    // Set read-only permissions on the config file for user, group, and others.
-    let perms = PermissionsExt::from_mode(0o444);
-    std::fs::set_permissions(path, perms)?;
-    Ok(())
-}
+    use super::*;
+
+    fn settings(package_id: &str, owner: &str, created: u64) -> PackageSettings {
+        PackageSettings {
+            encrypted_keys: Vec::new(),
+            threshold: 1,
+            package_id: package_id.to_string(),
+            archive_id: String::new(),
+            owner: owner.to_string(),
+            owner_public_key: String::new(),
+            created: created,
+            recipient: String::new(),
+        }
+    }
+
+    #[test]
+    fn associated_data_covers_package_metadata() {
+        let original = settings("AAAA", "Owen", 1000).associated_data();
+
+        assert!(original == settings("AAAA", "Owen", 1000).associated_data());
+        assert!(original != settings("BBBB", "Owen", 1000).associated_data());
+        assert!(original != settings("AAAA", "Olga", 1000).associated_data());
+        assert!(original != settings("AAAA", "Owen", 1001).associated_data());
+
+        let mut signed = settings("AAAA", "Owen", 1000);
+        signed.owner_public_key = String::from("S2V5");
+        let mut resigned = settings("AAAA", "Owen", 1000);
+        resigned.owner_public_key = String::from("T3RoZXI=");
+        assert!(original != signed.associated_data());
+        assert!(signed.associated_data() != resigned.associated_data());
+    }
 
-#[cfg(target_os = "windows")]
-fn set_readonly(_path: &PathBuf) -> Result<(), Error> {
-    // TODO: Set readonly flag on file
-    Ok(())
+    #[test]
+    fn older_packages_have_no_associated_data() {
+        let settings: PackageSettings =
+            serde_json::from_str(r#"{ "encrypted_keys": [] }"#).unwrap();
+
+        assert!(settings.associated_data().is_empty());
+        assert!(!settings.should_be_signed());
+        assert!(self::settings("AAAA", "Owen", 1000).should_be_signed());
+    }
+
+    #[test]
+    fn packages_compiled_together_share_associated_data() {
+        let mut alice = settings("AAAA", "Owen", 1000);
+        let mut bob = settings("BBBB", "Owen", 1000);
+        assert!(alice.associated_data() != bob.associated_data());
+
+        alice.archive_id = String::from("CCCC");
+        bob.archive_id = String::from("CCCC");
+        assert!(alice.associated_data() == bob.associated_data());
+        assert!(alice.associated_data() != settings("CCCC", "Olga", 1000).associated_data());
+    }
 }
@@ -1,3 +1,4 @@
+use super::libsodium::KdfProfile;
 pub use quicli::prelude::*;
 use structopt::StructOpt;
 
@@ -24,6 +25,26 @@ pub enum SubCommand {
     /// Decrypt a backup
     #[structopt(name = "decrypt")]
     Decrypt(DecryptArgs),
+
+    /// Check that a compiled backup is intact and up to date
+    #[structopt(name = "verify")]
+    Verify(VerifyArgs),
+
+    /// Add, remove, list, or rename backup holders
+    #[structopt(name = "recipient")]
+    Recipient(RecipientArgs),
+
+    /// Replace the master key, e.g. because a backup was stolen
+    #[structopt(name = "rekey")]
+    Rekey(RekeyArgs),
+
+    /// Show the backups you've given out, and which need to be reissued
+    #[structopt(name = "status")]
+    Status(StatusArgs),
+
+    /// Check a project for problems before compiling
+    #[structopt(name = "doctor")]
+    Doctor(DoctorArgs),
 }
 
 #[derive(StructOpt)]
@@ -39,21 +60,60 @@ pub struct InitArgs {
     /// Specify your name (for readme that gets distributed to backup holders)
     #[structopt(short = "o", long = "owner")]
     pub owner: Option<String>,
+
+    /// How hard passphrases are to brute force: sensitive (needs ~1 GiB RAM
+    /// to decrypt), moderate (~256 MiB), or interactive (~64 MiB)
+    #[structopt(long = "kdf-profile", default_value = "sensitive")]
+    pub kdf_profile: KdfProfile,
+
+    /// How many other backup holders must enter their passphrases to unlock
+    /// a backup
+    #[structopt(short = "t", long = "threshold", default_value = "1")]
+    pub threshold: u8,
+
+    /// Don't prompt for an owner passphrase; store project settings
+    /// (including the master key and all recipient passphrases) unencrypted
+    #[structopt(long = "no-owner-passphrase")]
+    pub no_owner_passphrase: bool,
 }
 
 #[derive(StructOpt)]
 pub struct CompileArgs {
-    /// The destination directory where you want to generate the backup
-    #[structopt(name = "dest-dir", parse(from_os_str))]
-    pub dest_dir: std::path::PathBuf,
+    /// The destination directory where you want to generate the backup. With
+    /// --all, each recipient's backup goes in a directory inside this one.
+    #[structopt(name = "dest-dir", parse(from_os_str), required_unless = "dry_run")]
+    pub dest_dir: Option<std::path::PathBuf>,
 
     /// The recipient for whom you're compiling your backup
-    #[structopt(short = "r", long = "recipient")]
-    pub recipient: String,
+    #[structopt(
+        short = "r",
+        long = "recipient",
+        raw(required_unless_one = r#"&["all", "dry_run"]"#)
+    )]
+    pub recipient: Option<String>,
+
+    /// Compile a backup for every recipient
+    #[structopt(long = "all", conflicts_with = "recipient")]
+    pub all: bool,
+
+    /// Replace a backup already at the destination, only rewriting the files
+    /// that changed
+    #[structopt(long = "update")]
+    pub update: bool,
+
+    /// List the files that would go into the backup, after applying any
+    /// .kinignore files, without compiling anything
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
 
     /// The project directory
     #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
     pub project_dir: Option<std::path::PathBuf>,
+
+    /// Override the project's key derivation profile for this backup:
+    /// sensitive, moderate, or interactive
+    #[structopt(long = "kdf-profile")]
+    pub kdf_profile: Option<KdfProfile>,
 }
 
 #[derive(StructOpt)]
@@ -62,9 +122,152 @@ pub struct DecryptArgs {
     #[structopt(short = "b", long = "backup-dir", parse(from_os_str))]
     pub backup_dir: Option<std::path::PathBuf>,
 
-    /// The destination decrypted archive path
+    /// The destination decrypted archive path, or with --extract, the
+    /// directory to extract into
     #[structopt(short = "d", long = "destination", parse(from_os_str))]
     pub destination: Option<std::path::PathBuf>,
+
+    /// Extract the decrypted files into an empty directory instead of saving
+    /// them as a .zip archive
+    #[structopt(short = "x", long = "extract")]
+    pub extract: bool,
+
+    /// List what's in the private archive without writing anything to disk
+    #[structopt(
+        long = "list",
+        conflicts_with = "extract",
+        conflicts_with = "destination"
+    )]
+    pub list: bool,
+
+    /// With --extract or --list, only the files matching these paths or
+    /// patterns (as in .gitignore, e.g. "taxes/*.pdf"). A directory matches
+    /// everything in it.
+    #[structopt(long = "only")]
+    pub only: Vec<String>,
+}
+
+#[derive(StructOpt)]
+pub struct VerifyArgs {
+    /// The directory containing the compiled backup
+    #[structopt(name = "package-dir", parse(from_os_str))]
+    pub package_dir: std::path::PathBuf,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+
+    /// The recipient the backup was compiled for. Only needed for backups
+    /// compiled by older versions of Kin, which didn't record it.
+    #[structopt(short = "r", long = "recipient")]
+    pub recipient: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct RecipientArgs {
+    #[structopt(subcommand)]
+    pub cmd: RecipientCommand,
+}
+
+#[derive(StructOpt)]
+pub enum RecipientCommand {
+    /// Add a backup holder and generate their passphrase
+    #[structopt(name = "add")]
+    Add(RecipientAddArgs),
+
+    /// Remove a backup holder
+    #[structopt(name = "remove")]
+    Remove(RecipientRemoveArgs),
+
+    /// List the backup holders
+    #[structopt(name = "list")]
+    List(RecipientListArgs),
+
+    /// Change a backup holder's name
+    #[structopt(name = "rename")]
+    Rename(RecipientRenameArgs),
+
+    /// Give a backup holder a new passphrase, e.g. because they lost their
+    /// readme
+    #[structopt(name = "rotate")]
+    Rotate(RecipientRotateArgs),
+}
+
+#[derive(StructOpt)]
+pub struct RecipientAddArgs {
+    /// The new backup holder's name
+    pub name: String,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RecipientRemoveArgs {
+    /// The backup holder to remove
+    pub name: String,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RecipientListArgs {
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RecipientRenameArgs {
+    /// The backup holder's current name
+    pub name: String,
+
+    /// The backup holder's new name
+    #[structopt(name = "new-name")]
+    pub new_name: String,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RecipientRotateArgs {
+    /// The backup holder whose passphrase was exposed
+    pub name: String,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RekeyArgs {
+    /// The backup holder whose backup was stolen. Its readme shows their
+    /// passphrase, so they get a new one too.
+    #[structopt(long = "stolen")]
+    pub stolen: Option<String>,
+
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct StatusArgs {
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct DoctorArgs {
+    /// The project directory
+    #[structopt(short = "p", long = "project-dir", parse(from_os_str))]
+    pub project_dir: Option<std::path::PathBuf>,
 }
 
 pub fn parse() -> SubCommand {
@@ -138,6 +341,54 @@ mod tests {
         assert_eq!(init_args.owner.unwrap(), "chuck");
     }
 
+    #[test]
+    fn init_with_threshold() {
+        let args = ["kin", "init", "--threshold", "2"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let init_args = match parsed.cmd {
+            SubCommand::Init(args) => args,
+            _ => panic!("not an init subcommand"),
+        };
+
+        assert_eq!(init_args.threshold, 2);
+    }
+
+    #[test]
+    fn init_kdf_profile_defaults_to_sensitive() {
+        let args = ["kin", "init"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let init_args = match parsed.cmd {
+            SubCommand::Init(args) => args,
+            _ => panic!("not an init subcommand"),
+        };
+
+        assert_eq!(init_args.kdf_profile, KdfProfile::Sensitive);
+    }
+
+    #[test]
+    fn compile_with_kdf_profile() {
+        let args = [
+            "kin",
+            "compile",
+            "~/temp",
+            "--recipient",
+            "foo@bar.baz",
+            "--kdf-profile",
+            "moderate",
+        ]
+        .iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let compile_args = match parsed.cmd {
+            SubCommand::Compile(args) => args,
+            _ => panic!("not a compile subcommand"),
+        };
+
+        assert_eq!(compile_args.kdf_profile, Some(KdfProfile::Moderate));
+    }
+
     #[test]
     fn compile_with_destination() {
         let args = ["kin", "compile", "~/temp", "--recipient", "foo@bar.baz"].iter();
@@ -148,8 +399,51 @@ mod tests {
             _ => panic!("not a compile subcommand"),
         };
 
-        let destination = compile_args.dest_dir.to_str().unwrap();
-        assert_eq!(destination, "~/temp");
+        let destination = compile_args.dest_dir.unwrap();
+        assert_eq!(destination.to_str().unwrap(), "~/temp");
+    }
+
+    #[test]
+    fn compile_all() {
+        let args = ["kin", "compile", "--all", "/media/usb"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let compile_args = match parsed.cmd {
+            SubCommand::Compile(args) => args,
+            _ => panic!("not a compile subcommand"),
+        };
+
+        assert!(compile_args.all);
+        assert_eq!(compile_args.recipient, None);
+
+        let both = ["kin", "compile", "--all", "-r", "Bob", "/media/usb"].iter();
+        assert!(CliArgs::from_iter_safe(both).is_err());
+
+        let neither = ["kin", "compile", "/media/usb"].iter();
+        assert!(CliArgs::from_iter_safe(neither).is_err());
+    }
+
+    #[test]
+    fn compile_dry_run() {
+        let args = ["kin", "compile", "--all", "--dry-run", "/media/usb"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let compile_args = match parsed.cmd {
+            SubCommand::Compile(args) => args,
+            _ => panic!("not a compile subcommand"),
+        };
+
+        assert!(compile_args.dry_run);
+        assert!(!compile_args.update);
+
+        let alone = ["kin", "compile", "--dry-run"].iter();
+        let compile_args = match CliArgs::from_iter_safe(alone).unwrap().cmd {
+            SubCommand::Compile(args) => args,
+            _ => panic!("not a compile subcommand"),
+        };
+        assert!(compile_args.dry_run);
+        assert_eq!(compile_args.dest_dir, None);
+        assert_eq!(compile_args.recipient, None);
     }
 
     #[test]
@@ -194,6 +488,7 @@ mod tests {
 
         assert_eq!(decrypt_command.backup_dir, None);
         assert_eq!(decrypt_command.destination, None);
+        assert!(!decrypt_command.extract);
     }
 
     #[test]
@@ -220,4 +515,126 @@ mod tests {
         assert_eq!(backup_dir.to_str().unwrap(), "~/foo");
         assert_eq!(destination.to_str().unwrap(), "~/bar");
     }
+
+    #[test]
+    fn decrypt_extract() {
+        let args = ["kin", "decrypt", "--extract", "-d", "~/bar"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let decrypt_command = match parsed.cmd {
+            SubCommand::Decrypt(args) => args,
+            _ => panic!("not a decrypt subcommand"),
+        };
+
+        assert_eq!(
+            decrypt_command.destination.unwrap().to_str().unwrap(),
+            "~/bar"
+        );
+        assert!(decrypt_command.extract);
+        assert!(!decrypt_command.list);
+    }
+
+    #[test]
+    fn decrypt_only() {
+        let args = [
+            "kin",
+            "decrypt",
+            "-x",
+            "-d",
+            "~/bar",
+            "--only",
+            "mortgage.pdf",
+            "--only",
+            "taxes/*.pdf",
+        ]
+        .iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let decrypt_command = match parsed.cmd {
+            SubCommand::Decrypt(args) => args,
+            _ => panic!("not a decrypt subcommand"),
+        };
+
+        assert_eq!(decrypt_command.only, vec!["mortgage.pdf", "taxes/*.pdf"]);
+    }
+
+    #[test]
+    fn decrypt_list() {
+        let args = ["kin", "decrypt", "--list", "-b", "/media/usb"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let decrypt_command = match parsed.cmd {
+            SubCommand::Decrypt(args) => args,
+            _ => panic!("not a decrypt subcommand"),
+        };
+        assert!(decrypt_command.list);
+        assert!(decrypt_command.only.is_empty());
+
+        let with_dest = ["kin", "decrypt", "--list", "-d", "~/bar"].iter();
+        assert!(CliArgs::from_iter_safe(with_dest).is_err());
+    }
+
+    #[test]
+    fn verify_with_proj_dir() {
+        let args = ["kin", "verify", "/media/usb", "-p", "~/backup"].iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let verify_args = match parsed.cmd {
+            SubCommand::Verify(args) => args,
+            _ => panic!("not a verify subcommand"),
+        };
+
+        assert_eq!(verify_args.package_dir.to_str().unwrap(), "/media/usb");
+        assert_eq!(
+            verify_args.project_dir.unwrap().to_str().unwrap(),
+            "~/backup"
+        );
+        assert_eq!(verify_args.recipient, None);
+    }
+
+    #[test]
+    fn recipient_rename() {
+        let args = [
+            "kin",
+            "recipient",
+            "rename",
+            "Bob",
+            "Robert",
+            "-p",
+            "~/backup",
+        ]
+        .iter();
+
+        let parsed = CliArgs::from_iter(args);
+        let rename_args = match parsed.cmd {
+            SubCommand::Recipient(RecipientArgs {
+                cmd: RecipientCommand::Rename(args),
+            }) => args,
+            _ => panic!("not a recipient rename subcommand"),
+        };
+
+        assert_eq!(rename_args.name, "Bob");
+        assert_eq!(rename_args.new_name, "Robert");
+        assert_eq!(
+            rename_args.project_dir.unwrap().to_str().unwrap(),
+            "~/backup"
+        );
+    }
+
+    #[test]
+    fn rekey_stolen() {
+        let args = ["kin", "rekey", "--stolen", "Bob"].iter();
+
+        let rekey_args = match CliArgs::from_iter(args).cmd {
+            SubCommand::Rekey(args) => args,
+            _ => panic!("not a rekey subcommand"),
+        };
+        assert_eq!(rekey_args.stolen, Some(String::from("Bob")));
+
+        let rekey_args = match CliArgs::from_iter(["kin", "rekey"].iter()).cmd {
+            SubCommand::Rekey(args) => args,
+            _ => panic!("not a rekey subcommand"),
+        };
+        assert_eq!(rekey_args.stolen, None);
+    }
 }
@@ -1,12 +1,19 @@
 use super::backuppackage::BackupPackage;
 use super::cmdline::DecryptArgs;
+use super::fsutil;
 use super::libsodium;
-use super::libsodium::MasterKey;
+use super::libsodium::{Decryptor, KeyShare, MasterKey};
+use super::manifest::Manifest;
 use super::ui;
 use failure::bail;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::info;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use zip::read::ZipFile;
 
 pub fn run(args: &DecryptArgs) -> Result<(), failure::Error> {
     let source_dir = match &args.backup_dir {
@@ -14,27 +21,138 @@ pub fn run(args: &DecryptArgs) -> Result<(), failure::Error> {
         None => std::env::current_dir()?,
     };
 
+    let filter = EntryFilter::new(&args.only)?;
+    if !args.only.is_empty() && !args.list && !args.extract {
+        bail!("--only works with --extract or --list");
+    }
+
     let backup_package = BackupPackage::from(&source_dir);
+    verify_package(&backup_package)?;
+
+    if args.list {
+        let master_key = unlock_master_key(&backup_package)?;
+        let mut decryptor = open_private_archive(&backup_package, &master_key)?;
+        return list_archive(&mut decryptor, &filter);
+    }
+
+    if args.extract {
+        let dest_dir = match &args.destination {
+            Some(path) => path.to_owned(),
+            None => prompt_dest_dir()?,
+        };
+
+        // Checked before asking for passphrases, and again right before
+        // anything is written
+        fsutil::ensure_empty_dir(&dest_dir)?;
+        info!("Extracting to {}", dest_dir.display());
+
+        let master_key = unlock_master_key(&backup_package)?;
+        let mut decryptor = open_private_archive(&backup_package, &master_key)?;
+        let count = extract_archive(&mut decryptor, &dest_dir, &filter)?;
+        println!("Extracted {} files to {}", count, dest_dir.display());
+
+        return Ok(());
+    }
 
     let dest_archive = match &args.destination {
         Some(path) => path.to_owned(),
         None => prompt_dest_archive()?,
     };
 
-    info!("Extracting to {}", dest_archive.to_str().unwrap());
+    info!("Extracting to {}", dest_archive.display());
+
+    let master_key = unlock_master_key(&backup_package)?;
+
+    decrypt_archive(&backup_package, &dest_archive, master_key)?;
+
+    Ok(())
+}
+
+/// Makes sure the package is exactly what the owner compiled, before anyone
+/// types a passphrase into it.
+fn verify_package(backup_package: &BackupPackage) -> Result<(), failure::Error> {
+    if !backup_package.manifest_path().exists() {
+        if backup_package.should_be_signed()? {
+            bail!("this backup's signed manifest is missing, so it may have been tampered with; don't use it, and tell the other backup holders");
+        }
+
+        println!("WARNING: This backup isn't signed, so there's no way to tell whether it was");
+        println!("changed after it was made.");
+        return Ok(());
+    }
+
+    let manifest = Manifest::read_verified(backup_package)?;
 
-    let passphrase = rpassword::read_password_from_tty(Some("Enter passphrase: "))?;
-    let master_key = backup_package.decrypt_master_key(&passphrase)?;
+    let changed = manifest.changed_files(backup_package)?;
+    if !changed.is_empty() {
+        bail!(
+            "these files have changed since {} made this backup: {}",
+            manifest.owner,
+            changed.join(", ")
+        );
+    }
+
+    let unlisted = manifest.unlisted_files(backup_package)?;
+    if !unlisted.is_empty() {
+        bail!(
+            "these files were added after {} made this backup: {}",
+            manifest.owner,
+            unlisted.join(", ")
+        );
+    }
 
-    decrypt_archive(
-        &backup_package.private_archive_path(),
-        &dest_archive,
-        master_key,
-    )?;
+    // Anyone could have re-signed it, so this doesn't show who made it. The
+    // private archive only decrypts with the key its owner compiled it with.
+    println!("Every file in this backup matches its signed manifest.");
+    println!(
+        "Signing key fingerprint: {}",
+        manifest.public_key()?.fingerprint()
+    );
+    println!(
+        "If this isn't {}'s key, the private files won't decrypt.",
+        manifest.owner
+    );
 
     Ok(())
 }
 
+fn unlock_master_key(backup_package: &BackupPackage) -> Result<MasterKey, failure::Error> {
+    let threshold = backup_package.threshold()?;
+
+    if threshold <= 1 {
+        let passphrase = ui::prompt_passphrase("Enter passphrase: ")?;
+        return backup_package.decrypt_master_key(&passphrase);
+    }
+
+    println!(
+        "This backup needs passphrases from {} other backup holders.",
+        threshold
+    );
+
+    let mut shares: Vec<KeyShare> = Vec::new();
+    while shares.len() < threshold as usize {
+        let prompt = format!("Enter passphrase {} of {}: ", shares.len() + 1, threshold);
+        let passphrase = ui::prompt_passphrase(&prompt)?;
+
+        let share = match backup_package.decrypt_key_share(&passphrase)? {
+            Some(share) => share,
+            None => {
+                println!("That passphrase doesn't unlock this backup. Try again.");
+                continue;
+            }
+        };
+
+        if shares.iter().any(|x| x.index() == share.index()) {
+            println!("That passphrase was already entered. Ask another backup holder.");
+            continue;
+        }
+
+        shares.push(share);
+    }
+
+    MasterKey::from_shares(&shares)
+}
+
 fn prompt_dest_archive() -> Result<PathBuf, failure::Error> {
     println!("Where do you want to save the decrypted .zip archive?");
 
@@ -47,23 +165,43 @@ fn prompt_dest_archive() -> Result<PathBuf, failure::Error> {
         let path = PathBuf::from(path);
         let dir = path.parent().unwrap();
 
-        if dir.to_str().unwrap().len() == 0 {
+        if dir.as_os_str().is_empty() {
             // User probably just entered a file name and expects it to go in
             // the current working directory
             return Ok(path);
         } else if dir.is_dir() {
             return Ok(path);
         } else {
-            println!("{} doesn't exist.", dir.to_str().unwrap());
+            println!("{} doesn't exist.", dir.display());
+        }
+    }
+}
+
+fn prompt_dest_dir() -> Result<PathBuf, failure::Error> {
+    println!("Which folder do you want to put the decrypted files in? It must be empty,");
+    println!("or not exist yet.");
+
+    loop {
+        let path = ui::prompt("Enter folder path: ")?;
+        if path.len() == 0 {
+            continue;
+        }
+
+        let path = PathBuf::from(path);
+        match fsutil::ensure_empty_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) => println!("{}", e),
         }
     }
 }
 
 fn decrypt_archive(
-    encrypted_archive_path: &PathBuf,
+    backup_package: &BackupPackage,
     dest_path: &PathBuf,
     master_key: MasterKey,
 ) -> Result<(), failure::Error> {
+    let encrypted_archive_path = backup_package.private_archive_path();
+
     let dest_file = OpenOptions::new()
         .create_new(true)
         .write(true)
@@ -71,19 +209,410 @@ fn decrypt_archive(
 
     let mut dest_file = match dest_file {
         Ok(file) => file,
-        Err(err) => bail!("Unable to open {}: {}", dest_path.to_str().unwrap(), err),
+        Err(err) => bail!("Unable to open {}: {}", dest_path.display(), err),
     };
 
     let mut reader = match File::open(&encrypted_archive_path) {
         Ok(file) => file,
         Err(err) => bail!(
             "Unable to open {}: {}",
-            encrypted_archive_path.to_str().unwrap(),
+            encrypted_archive_path.display(),
             err
         ),
     };
 
-    libsodium::decrypt(&master_key, &mut reader, &mut dest_file)?;
+    let associated_data = backup_package.associated_data()?;
+    libsodium::decrypt(&master_key, &associated_data, &mut reader, &mut dest_file)?;
 
     Ok(())
 }
+
+/// Unzips the private archive into `dest_dir` as it's decrypted, so the
+/// decrypted archive itself is never written to disk. Returns how many files
+/// were extracted.
+fn extract_archive<R: Read>(
+    decryptor: &mut Decryptor<R>,
+    dest_dir: &PathBuf,
+    filter: &EntryFilter,
+) -> Result<usize, failure::Error> {
+    fsutil::ensure_empty_dir(dest_dir)?;
+
+    let mut count = 0;
+    let result = read_entries(decryptor, |entry| {
+        if !filter.matches(entry.name()) {
+            // It still has to be decrypted, since each chunk of the stream
+            // is authenticated along with the ones before it
+            return Ok(());
+        }
+
+        let dest_path = dest_dir.join(entry_path(entry.name())?);
+
+        if entry.name().ends_with('/') {
+            fs::create_dir_all(&dest_path)?;
+            return Ok(());
+        }
+
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Never replaces anything, even if the archive lists a file twice
+        let dest_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&dest_path);
+        let mut dest_file = match dest_file {
+            Ok(file) => file,
+            Err(err) => bail!("Unable to create {}: {}", dest_path.display(), err),
+        };
+
+        std::io::copy(entry, &mut dest_file)?;
+        count += 1;
+        Ok(())
+    });
+
+    if let Err(err) = result {
+        bail!(
+            "{}; anything already extracted to {} may be incomplete",
+            err,
+            dest_dir.display()
+        );
+    }
+
+    filter.check_matched(count)?;
+    Ok(count)
+}
+
+/// Prints what's in the private archive. Everything is decrypted in memory,
+/// one chunk at a time, and thrown away once it's been counted.
+fn list_archive<R: Read>(
+    decryptor: &mut Decryptor<R>,
+    filter: &EntryFilter,
+) -> Result<(), failure::Error> {
+    println!("{:>12}  {:16}  {}", "Size", "Modified", "Name");
+
+    let mut count = 0;
+    let mut total = 0;
+    read_entries(decryptor, |entry| {
+        if entry.name().ends_with('/') || !filter.matches(entry.name()) {
+            return Ok(());
+        }
+
+        let size = std::io::copy(entry, &mut std::io::sink())?;
+        let modified = entry.last_modified();
+        println!(
+            "{:>12}  {:04}-{:02}-{:02} {:02}:{:02}  {}",
+            size,
+            modified.year(),
+            modified.month(),
+            modified.day(),
+            modified.hour(),
+            modified.minute(),
+            entry.name()
+        );
+
+        count += 1;
+        total += size;
+        Ok(())
+    })?;
+
+    println!("{:>12}  {:16}  {} files", total, "", count);
+    filter.check_matched(count)
+}
+
+/// Calls `f` with each entry of the decrypted zip archive, then reads the
+/// rest of it, since that's the only way to know it wasn't cut short. If the
+/// archive is damaged, that's the error returned, whatever else went wrong
+/// along the way because of it.
+pub fn read_entries<R, F>(decryptor: &mut Decryptor<R>, mut f: F) -> Result<(), failure::Error>
+where
+    R: Read,
+    F: FnMut(&mut ZipFile) -> Result<(), failure::Error>,
+{
+    let result = read_entries_unchecked(decryptor, &mut f);
+    decryptor.check()?;
+    result
+}
+
+fn read_entries_unchecked<R, F>(
+    decryptor: &mut Decryptor<R>,
+    f: &mut F,
+) -> Result<(), failure::Error>
+where
+    R: Read,
+    F: FnMut(&mut ZipFile) -> Result<(), failure::Error>,
+{
+    while let Some(mut entry) = zip::read::read_zipfile_from_stream(decryptor)? {
+        let result = f(&mut entry);
+
+        // Whatever `f` left unread is read here, where damage is an error.
+        // Otherwise the zip crate reads it when the entry is dropped, and
+        // panics.
+        let skipped = std::io::copy(&mut entry, &mut std::io::sink());
+        result?;
+        skipped?;
+    }
+
+    // The zip's central directory is still left
+    std::io::copy(decryptor, &mut std::io::sink())?;
+    Ok(())
+}
+
+fn open_private_archive(
+    backup_package: &BackupPackage,
+    master_key: &MasterKey,
+) -> Result<Decryptor<BufReader<File>>, failure::Error> {
+    let encrypted_archive_path = backup_package.private_archive_path();
+    let reader = match File::open(&encrypted_archive_path) {
+        Ok(file) => BufReader::new(file),
+        Err(err) => bail!(
+            "Unable to open {}: {}",
+            encrypted_archive_path.display(),
+            err
+        ),
+    };
+
+    let associated_data = backup_package.associated_data()?;
+    Decryptor::new(master_key, &associated_data, reader)
+}
+
+/// The decrypt program takes no arguments, except `--list` to see what's in
+/// the backup without extracting it
+pub fn list_requested() -> bool {
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    args == [OsString::from("--list")]
+}
+
+/// Picks which files in the private archive to list or extract
+struct EntryFilter {
+    patterns: Vec<String>,
+    matcher: Gitignore,
+}
+
+impl EntryFilter {
+    /// With no patterns, everything matches
+    fn new(patterns: &[String]) -> Result<EntryFilter, failure::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        builder.case_insensitive(true)?;
+        for pattern in patterns.iter() {
+            if let Err(e) = builder.add_line(None, pattern) {
+                bail!("invalid pattern \"{}\": {}", pattern, e);
+            }
+        }
+
+        Ok(EntryFilter {
+            patterns: patterns.to_vec(),
+            matcher: builder.build()?,
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        if self.patterns.is_empty() {
+            return true;
+        }
+
+        // Unsafe names never match, so they're left out rather than refused
+        match entry_path(name) {
+            Ok(path) => self
+                .matcher
+                .matched_path_or_any_parents(&path, name.ends_with('/'))
+                .is_ignore(),
+            Err(_) => false,
+        }
+    }
+
+    /// A filter that matched nothing was probably mistyped
+    fn check_matched(&self, count: usize) -> Result<(), failure::Error> {
+        if count == 0 && !self.patterns.is_empty() {
+            bail!(
+                "nothing in this backup matches {}; use --list to see what's in it",
+                self.patterns.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Where an entry in the archive goes, relative to the directory it's
+/// extracted into. Refuses anything that would land outside that directory.
+fn entry_path(name: &str) -> Result<PathBuf, failure::Error> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => bail!("the private archive contains an unsafe file name: {}", name),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        bail!("the private archive contains an empty file name");
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::io::{Cursor, Write};
+
+    const AD: &[u8] = b"package 1";
+
+    /// A file spanning several chunks, between two small ones
+    fn encrypted_archive(key: &MasterKey) -> Vec<u8> {
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+        archive.start_file("a.txt", options).unwrap();
+        archive.write_all(b"first").unwrap();
+        archive.start_file("big.bin", options).unwrap();
+        archive.write_all(&[7; 100000]).unwrap();
+        archive.start_file("z.txt", options).unwrap();
+        archive.write_all(b"last").unwrap();
+        let zipped = archive.finish().unwrap().into_inner();
+
+        let mut encrypted = Vec::new();
+        libsodium::encrypt(key, AD, &mut &zipped[..], &mut encrypted).unwrap();
+        encrypted
+    }
+
+    /// Truncated and tampered with, both in the middle of big.bin
+    fn damaged_archives(key: &MasterKey) -> Vec<Vec<u8>> {
+        let archive = encrypted_archive(key);
+        let middle = archive.len() / 2;
+
+        let mut tampered = archive.clone();
+        tampered[middle] ^= 0x01;
+
+        vec![archive[0..middle].to_vec(), tampered]
+    }
+
+    fn filter(patterns: &[&str]) -> EntryFilter {
+        let patterns: Vec<String> = patterns.iter().map(|x| x.to_string()).collect();
+        EntryFilter::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn extract_damaged_archives() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+        let temp = TempDir::new();
+
+        let dest_dir = temp.path().join("intact");
+        let archive = encrypted_archive(&key);
+        let mut decryptor = Decryptor::new(&key, AD, &archive[..]).unwrap();
+        assert_eq!(
+            extract_archive(&mut decryptor, &dest_dir, &filter(&[])).unwrap(),
+            3
+        );
+        assert_eq!(fs::read(dest_dir.join("z.txt")).unwrap(), b"last".to_vec());
+
+        // big.bin is either extracted or skipped when the damage is found
+        for (i, damaged) in damaged_archives(&key).iter().enumerate() {
+            for (j, patterns) in [vec![], vec!["z.txt"]].iter().enumerate() {
+                let dest_dir = temp.path().join(format!("damaged-{}-{}", i, j));
+                let mut decryptor = Decryptor::new(&key, AD, &damaged[..]).unwrap();
+                assert!(extract_archive(&mut decryptor, &dest_dir, &filter(patterns)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn list_damaged_archives() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let archive = encrypted_archive(&key);
+        let mut decryptor = Decryptor::new(&key, AD, &archive[..]).unwrap();
+        assert!(list_archive(&mut decryptor, &filter(&[])).is_ok());
+
+        for damaged in damaged_archives(&key).iter() {
+            for patterns in [vec![], vec!["z.txt"]].iter() {
+                let mut decryptor = Decryptor::new(&key, AD, &damaged[..]).unwrap();
+                assert!(list_archive(&mut decryptor, &filter(patterns)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn entry_filter() {
+        let everything = filter(&[]);
+        assert!(everything.matches("house/mortgage.pdf"));
+        assert!(everything.check_matched(0).is_ok());
+
+        let mortgage = filter(&["Mortgage.pdf"]);
+        assert!(mortgage.matches("house/mortgage.pdf"));
+        assert!(mortgage.matches("mortgage.pdf"));
+        assert!(!mortgage.matches("house/deed.pdf"));
+        assert!(mortgage.check_matched(0).is_err());
+
+        let taxes = filter(&["taxes/*.pdf", "house"]);
+        assert!(taxes.matches("taxes/2019.pdf"));
+        assert!(!taxes.matches("taxes/2019/w2.pdf"));
+        assert!(!taxes.matches("old/taxes/2019.pdf"));
+        assert!(taxes.matches("house/deed.pdf"));
+        assert!(taxes.matches("house/"));
+        assert!(!taxes.matches("../house/deed.pdf"));
+    }
+
+    #[test]
+    fn entry_paths() {
+        assert_eq!(entry_path("a.txt").unwrap(), PathBuf::from("a.txt"));
+        assert_eq!(
+            entry_path("pass/./email.gpg").unwrap(),
+            PathBuf::from("pass").join("email.gpg")
+        );
+        assert_eq!(entry_path("pass/").unwrap(), PathBuf::from("pass"));
+
+        assert!(entry_path("../evil").is_err());
+        assert!(entry_path("pass/../../evil").is_err());
+        assert!(entry_path("/etc/passwd").is_err());
+        assert!(entry_path("").is_err());
+        assert!(entry_path("./").is_err());
+
+        #[cfg(windows)]
+        {
+            assert!(entry_path("..\\evil").is_err());
+            assert!(entry_path("C:\\evil").is_err());
+        }
+    }
+
+    #[test]
+    fn verify_package_rejects_added_files() {
+        crate::libsodium::init().unwrap();
+
+        let temp = TempDir::new();
+        let package = BackupPackage::from(temp.path());
+        let key = crate::libsodium::SigningKey::new();
+        std::fs::create_dir_all(package.config_dir_path()).unwrap();
+        std::fs::create_dir_all(package.decrypt_exe_dir()).unwrap();
+        std::fs::write(
+            package.config_file_path(),
+            format!(
+                "{{\"encrypted_keys\": [], \"owner_public_key\": \"{}\"}}",
+                key.public_key().encode_base64()
+            ),
+        )
+        .unwrap();
+        std::fs::write(package.decrypt_exe_dir().join("decrypt"), "").unwrap();
+
+        Manifest::build(&package, &String::from("Owen"), &key.public_key())
+            .unwrap()
+            .write(&package, &key)
+            .unwrap();
+        verify_package(&package).unwrap();
+
+        // Other things on the drive aren't part of the package
+        std::fs::write(temp.path().join("photos.zip"), "").unwrap();
+        verify_package(&package).unwrap();
+
+        std::fs::write(package.decrypt_exe_dir().join("decrypt.exe"), "").unwrap();
+        let error = verify_package(&package).err().unwrap().to_string();
+        assert!(error.contains("private/decrypt.exe"));
+    }
+}
@@ -6,35 +6,43 @@ pub fn ensure_empty_dir(path: &Path) -> Result<(), Error> {
     if !path.exists() {
         match fs::create_dir(path) {
             Ok(x) => return Ok(x),
-            Err(e) => bail!("unable to create {}: {}", path.to_str().unwrap(), e),
+            Err(e) => bail!("unable to create {}: {}", path.display(), e),
         };
     }
 
     let metadata = match fs::metadata(path) {
         Ok(m) => m,
-        Err(e) => bail!(
-            "unable to get metadata for {}: {}",
-            path.to_str().unwrap(),
-            e
-        ),
+        Err(e) => bail!("unable to get metadata for {}: {}", path.display(), e),
     };
 
     if !metadata.is_dir() {
-        bail!("{} is not a directory", path.to_str().unwrap());
+        bail!("{} is not a directory", path.display());
     }
 
     let is_not_empty = match fs::read_dir(&path) {
         Ok(mut contents) => contents.any(|_| true),
-        Err(e) => bail!(
-            "unable to list contents of {}: {}",
-            path.to_str().unwrap(),
-            e
-        ),
+        Err(e) => bail!("unable to list contents of {}: {}", path.display(), e),
     };
 
     if is_not_empty {
-        bail!("{} is not empty", path.to_str().unwrap());
+        bail!("{} is not empty", path.display());
     }
 
     Ok(())
 }
+
+#[cfg(target_os = "linux")]
+pub fn set_readonly(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Set read-only permissions for user, group, and others.
+    let perms = PermissionsExt::from_mode(0o444);
+    fs::set_permissions(path, perms)?;
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn set_readonly(_path: &Path) -> Result<(), Error> {
+    // TODO: Set readonly flag on file
+    Ok(())
+}
@@ -1,24 +1,48 @@
 use super::fsutil;
 use super::kinsettings::KinSettings;
-use std::path::PathBuf;
+use super::libsodium;
+use super::libsodium::SecretString;
+use super::registry::PackageRegistry;
+use super::ui;
+use failure::bail;
+use ignore::WalkBuilder;
+use std::cell::RefCell;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Lists files to leave out of the backup, using the same syntax as
+/// .gitignore. One can go in the project directory, or in any directory in
+/// `public` or `private`.
+pub const IGNORE_FILE_NAME: &str = ".kinignore";
+
+/// A file or directory that goes into one of the package's archives
+pub struct ProjectFile {
+    pub path: PathBuf,
+    /// Relative to the public or private directory, with `/` as the
+    /// separator
+    pub name: String,
+    pub is_dir: bool,
+}
 
 pub struct KinProject {
     path: PathBuf,
+
+    /// Remembered after the first prompt so we only ask once per invocation
+    owner_passphrase: RefCell<Option<SecretString>>,
 }
 
 impl KinProject {
     pub fn from(path: &PathBuf) -> KinProject {
         KinProject {
             path: path.to_owned(),
+            owner_passphrase: RefCell::new(None),
         }
     }
 
     pub fn init(path: &PathBuf) -> Result<KinProject, failure::Error> {
         fsutil::ensure_empty_dir(path)?;
 
-        let project = KinProject {
-            path: path.to_owned(),
-        };
+        let project = KinProject::from(path);
 
         let subdirs = [
             project.public_dir(),
@@ -49,8 +73,9 @@ impl KinProject {
         self.config_dir().join("config.json")
     }
 
-    pub fn temp_file(&self) -> PathBuf {
-        self.config_dir().join("temp")
+    /// Records every package compiled from this project
+    pub fn registry_file(&self) -> PathBuf {
+        self.config_dir().join("packages.json")
     }
 
     pub fn overview_readme_template(&self) -> PathBuf {
@@ -61,7 +86,276 @@ impl KinProject {
         self.path.join("decrypt-template.md")
     }
 
+    /// Everything in the public directory that isn't ignored
+    pub fn public_files(&self) -> Result<Vec<ProjectFile>, failure::Error> {
+        self.list_files(&self.public_dir())
+    }
+
+    /// Everything in the private directory that isn't ignored
+    pub fn private_files(&self) -> Result<Vec<ProjectFile>, failure::Error> {
+        self.list_files(&self.private_dir())
+    }
+
+    /// Summarizes what goes into every package besides its keys: the public
+    /// and private files, and the readme templates. It changes whenever any
+    /// of them do.
+    pub fn content_hash(&self) -> Result<String, failure::Error> {
+        let mut files = vec![
+            (
+                String::from("overview-template.md"),
+                self.overview_readme_template(),
+            ),
+            (
+                String::from("decrypt-template.md"),
+                self.decrypt_readme_template(),
+            ),
+        ];
+        for (prefix, listed) in [
+            ("public/", self.public_files()?),
+            ("private/", self.private_files()?),
+        ]
+        .iter()
+        {
+            for file in listed.iter().filter(|x| !x.is_dir) {
+                files.push((format!("{}{}", prefix, file.name), file.path.clone()));
+            }
+        }
+        files.sort();
+
+        let mut listing = String::new();
+        for (name, path) in files.iter() {
+            let mut file = File::open(path)?;
+            let hash = libsodium::to_hex(&libsodium::sha256_reader(&mut file)?);
+            listing.push_str(&format!("{}  {}\n", hash, name));
+        }
+
+        Ok(libsodium::to_hex(&libsodium::sha256(listing.as_bytes())))
+    }
+
     pub fn settings(&self) -> Result<KinSettings, failure::Error> {
-        KinSettings::read(&self.config_file())
+        let path = self.config_file();
+        if !KinSettings::is_encrypted(&path)? {
+            return KinSettings::read(&path, None);
+        }
+
+        let passphrase = self.owner_passphrase()?;
+        let settings = KinSettings::read(&path, Some(&passphrase));
+        if settings.is_err() {
+            self.owner_passphrase.replace(None);
+        }
+
+        settings
+    }
+
+    /// Saves the project settings, encrypting them if the project is
+    /// protected by an owner passphrase.
+    pub fn write_settings(&self, settings: &KinSettings) -> Result<(), failure::Error> {
+        let path = self.config_file();
+
+        let passphrase = if path.exists() && KinSettings::is_encrypted(&path)? {
+            Some(self.owner_passphrase()?)
+        } else {
+            self.owner_passphrase.borrow().clone()
+        };
+
+        settings.write(&path, passphrase.as_ref())
+    }
+
+    pub fn registry(&self) -> Result<PackageRegistry, failure::Error> {
+        PackageRegistry::read(&self.registry_file())
+    }
+
+    pub fn write_registry(&self, registry: &PackageRegistry) -> Result<(), failure::Error> {
+        registry.write(&self.registry_file())
+    }
+
+    /// Protects the project settings with a passphrase the next time they
+    /// are written. `None` leaves them unencrypted.
+    pub fn set_owner_passphrase(&self, passphrase: Option<SecretString>) {
+        self.owner_passphrase.replace(passphrase);
+    }
+
+    /// Walks `dir`, which must be in the project directory, applying every
+    /// ignore file from the project directory down. Entries come out sorted,
+    /// with each directory before its contents.
+    ///
+    /// Symbolic links are followed, so what they point to is backed up as if
+    /// it were in the project. Broken links, and anything that isn't a file
+    /// or directory (sockets, FIFOs, devices), are skipped with a warning.
+    fn list_files(&self, dir: &Path) -> Result<Vec<ProjectFile>, failure::Error> {
+        let top = dir.to_path_buf();
+        let walker = WalkBuilder::new(&self.path)
+            .standard_filters(false)
+            .parents(false)
+            .follow_links(true)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME)
+            .filter_entry(move |entry| entry.depth() == 0 || entry.path().starts_with(&top))
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+
+        let mut files = Vec::new();
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => match broken_link(&e) {
+                    Some(path) => {
+                        println!("WARNING: skipping {}: it's a broken link", path.display());
+                        continue;
+                    }
+                    None => bail!("unable to list {}: {}", dir.display(), e),
+                },
+            };
+
+            if entry.depth() == 0 || entry.path() == dir || entry.file_name() == IGNORE_FILE_NAME {
+                continue;
+            }
+
+            let is_dir = match entry.file_type() {
+                Some(t) if t.is_dir() => true,
+                Some(t) if t.is_file() => false,
+                _ => {
+                    println!(
+                        "WARNING: skipping {}: it isn't a regular file or directory",
+                        entry.path().display()
+                    );
+                    continue;
+                }
+            };
+
+            let mut components = Vec::new();
+            for component in entry.path().strip_prefix(dir)?.components() {
+                match component.as_os_str().to_str() {
+                    Some(x) => components.push(x),
+                    None => bail!(
+                        "{} can't be backed up because its name isn't valid UTF-8; rename it, or add it to a {} file",
+                        entry.path().display(),
+                        IGNORE_FILE_NAME
+                    ),
+                }
+            }
+
+            files.push(ProjectFile {
+                path: entry.path().to_path_buf(),
+                name: components.join("/"),
+                is_dir: is_dir,
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn owner_passphrase(&self) -> Result<SecretString, failure::Error> {
+        if let Some(passphrase) = self.owner_passphrase.borrow().as_ref() {
+            return Ok(passphrase.clone());
+        }
+
+        let passphrase = ui::prompt_passphrase("Enter owner passphrase: ")?;
+        self.owner_passphrase.replace(Some(passphrase.clone()));
+        Ok(passphrase)
+    }
+}
+
+/// A link that points at something that doesn't exist can't be followed.
+/// Returns the link's path if that's what went wrong.
+fn broken_link(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, err } => match err.io_error() {
+            Some(e) if e.kind() == std::io::ErrorKind::NotFound => Some(path),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::fs;
+
+    #[test]
+    fn ignore_files() {
+        let temp = TempDir::new();
+        let dir = temp.path();
+        let project = KinProject::init(dir).unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+
+        write(".kinignore", ".DS_Store\n*.swp\n");
+        write("public/will.pdf", "");
+        write("public/.DS_Store", "");
+        write("private/pass/.kinignore", "/.git/\n*.log\n!keep.log\n");
+        write("private/pass/.git/config", "");
+        write("private/pass/email.gpg", "");
+        write("private/pass/email.gpg.swp", "");
+        write("private/pass/debug.log", "");
+        write("private/pass/keep.log", "");
+        write("private/.git/config", "");
+
+        let names = |files: Vec<ProjectFile>| {
+            files
+                .into_iter()
+                .map(|x| {
+                    if x.is_dir {
+                        format!("{}/", x.name)
+                    } else {
+                        x.name
+                    }
+                })
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(names(project.public_files().unwrap()), vec!["will.pdf"]);
+        assert_eq!(
+            names(project.private_files().unwrap()),
+            vec![
+                ".git/",
+                ".git/config",
+                "pass/",
+                "pass/email.gpg",
+                "pass/keep.log"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_and_special_files() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::symlink;
+        use std::os::unix::net::UnixListener;
+
+        let temp = TempDir::new();
+        let dir = temp.path();
+        let project = KinProject::init(dir).unwrap();
+        let private = project.private_dir();
+        fs::create_dir(private.join("photos")).unwrap();
+        fs::write(private.join("photos/cat.jpg"), "").unwrap();
+        symlink(private.join("photos"), private.join("pictures")).unwrap();
+        symlink(private.join("missing"), private.join("broken")).unwrap();
+        let _socket = UnixListener::bind(private.join("agent.sock")).unwrap();
+
+        let names: Vec<String> = project
+            .private_files()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["photos", "photos/cat.jpg", "pictures", "pictures/cat.jpg"]
+        );
+
+        symlink(&private, private.join("photos/loop")).unwrap();
+        assert!(project.private_files().is_err());
+        fs::remove_file(private.join("photos/loop")).unwrap();
+
+        let bad_name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(private.join(bad_name), "").unwrap();
+        assert!(project.private_files().is_err());
     }
 }
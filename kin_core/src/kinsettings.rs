@@ -1,48 +1,166 @@
-use super::libsodium::MasterKey;
+use super::libsodium::{
+    KdfParams, KdfProfile, MasterKey, PassphraseBox, SecretBytes, SecretString, SigningKey,
+};
 use super::{bail, Error};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::iter::Iterator;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Serialize, Deserialize)]
 pub struct KinRecipient {
     pub name: String,
-    pub passphrase: String,
+    pub passphrase: SecretString,
+}
+
+/// A master key replaced by `kin rekey`. Packages compiled with it still
+/// open with it, so it's kept for reference.
+#[derive(Serialize, Deserialize)]
+pub struct RetiredMasterKey {
+    pub generation: u32,
+    master_key: SecretString,
+    /// Seconds since the Unix epoch
+    pub retired: u64,
+}
+
+impl RetiredMasterKey {
+    pub fn master_key(&self) -> Result<MasterKey, Error> {
+        MasterKey::decode_base64(&self.master_key)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct KinSettings {
     owner: String,
-    master_key: String,
+    master_key: SecretString,
+
+    /// Goes up by one every time the master key is replaced
+    #[serde(default = "first_key_generation")]
+    master_key_generation: u32,
+    #[serde(default)]
+    retired_master_keys: Vec<RetiredMasterKey>,
+
     pub recipients: Vec<KinRecipient>,
+    #[serde(default)]
+    pub kdf_profile: KdfProfile,
+
+    /// How many of a recipient's peers must enter their passphrases to
+    /// unlock that recipient's backup
+    #[serde(default = "default_threshold")]
+    pub threshold: u8,
+
+    /// Signs compiled packages. Projects created before packages were signed
+    /// don't have one until their next compile.
+    #[serde(default)]
+    signing_key: Option<SecretString>,
 }
 
 impl KinSettings {
-    pub fn new(owner: &String, recipients: Vec<KinRecipient>) -> KinSettings {
+    pub fn new(
+        owner: &String,
+        recipients: Vec<KinRecipient>,
+        kdf_profile: KdfProfile,
+        threshold: u8,
+    ) -> KinSettings {
         KinSettings {
             owner: owner.clone(),
             master_key: MasterKey::new().encode_base64(),
+            master_key_generation: first_key_generation(),
+            retired_master_keys: Vec::new(),
             recipients: recipients,
+            kdf_profile: kdf_profile,
+            threshold: threshold,
+            signing_key: Some(SigningKey::new().encode_base64()),
         }
     }
 
-    pub fn write(&self, path: &PathBuf) -> Result<(), Error> {
-        let config_serialized = serde_json::to_string_pretty(self)?;
+    /// Writes the settings to disk. If an owner passphrase is given, the
+    /// settings are encrypted with it first.
+    pub fn write(
+        &self,
+        path: &PathBuf,
+        owner_passphrase: Option<&SecretString>,
+    ) -> Result<(), Error> {
+        let config_serialized = to_secret_json(self)?;
+
+        let encrypted = match owner_passphrase {
+            Some(passphrase) => {
+                let kdf = OWNER_KDF_PROFILE.params();
+                let sealed = PassphraseBox::seal(config_serialized.as_slice(), passphrase, &kdf)?;
+                let file = EncryptedSettingsFile {
+                    encrypted_settings: EncryptedSettings {
+                        data: sealed.data(),
+                        passphrase_salt: sealed.passphrase_salt(),
+                        nonce: sealed.nonce(),
+                        kdf_algorithm: kdf.algorithm(),
+                        kdf_opslimit: kdf.opslimit(),
+                        kdf_memlimit: kdf.memlimit(),
+                    },
+                };
+                Some(serde_json::to_vec_pretty(&file)?)
+            }
+            None => None,
+        };
 
         let file = File::create(path)?;
         let mut file = BufWriter::new(file);
-        file.write(config_serialized.as_bytes())?;
+        match encrypted {
+            Some(encrypted) => file.write_all(&encrypted)?,
+            None => file.write_all(config_serialized.as_slice())?,
+        }
         file.flush()?;
 
         Ok(())
     }
 
-    pub fn read(path: &PathBuf) -> Result<KinSettings, Error> {
+    /// Reads settings from disk. Encrypted settings need the owner passphrase.
+    pub fn read(
+        path: &PathBuf,
+        owner_passphrase: Option<&SecretString>,
+    ) -> Result<KinSettings, Error> {
+        // Sized up front, so the plaintext is never copied into a growing
+        // buffer that leaves unwiped pieces of it behind
+        let mut file = File::open(path)?;
+        let mut contents = SecretBytes::new(file.metadata()?.len() as usize);
+        file.read_exact(contents.as_mut_slice())?;
+
+        let encrypted = match serde_json::from_slice::<EncryptedSettingsFile>(contents.as_slice()) {
+            Ok(file) => file.encrypted_settings,
+            Err(_) => return Ok(serde_json::from_slice(contents.as_slice())?), // Not encrypted
+        };
+
+        let passphrase = match owner_passphrase {
+            Some(passphrase) => passphrase,
+            None => bail!("settings are encrypted; the owner passphrase is required"),
+        };
+
+        let kdf = KdfParams::new(
+            &encrypted.kdf_algorithm,
+            encrypted.kdf_opslimit,
+            encrypted.kdf_memlimit,
+        )?;
+        let sealed = PassphraseBox::new(
+            &encrypted.data,
+            &encrypted.passphrase_salt,
+            &encrypted.nonce,
+            kdf,
+        )?;
+
+        let decrypted = match sealed.open(passphrase) {
+            Ok(data) => data,
+            Err(_) => bail!("wrong owner passphrase"),
+        };
+
+        Ok(serde_json::from_slice(decrypted.as_slice())?)
+    }
+
+    /// Returns true if the settings file at `path` is protected by an owner
+    /// passphrase.
+    pub fn is_encrypted(path: &PathBuf) -> Result<bool, Error> {
         let file = File::open(path)?;
-        let settings = serde_json::from_reader(file)?;
-        Ok(settings)
+        Ok(serde_json::from_reader::<_, EncryptedSettingsFile>(file).is_ok())
     }
 
     pub fn owner(&self) -> String {
@@ -76,7 +194,371 @@ impl KinSettings {
         Ok(others)
     }
 
+    /// Makes sure a list of new recipient names can be used together
+    pub fn check_recipient_names(names: &[String]) -> Result<(), Error> {
+        for (i, name) in names.iter().enumerate() {
+            check_recipient_name(name)?;
+            if names[..i].iter().any(|x| same_name(x, name)) {
+                bail!("the recipient \"{}\" is listed more than once", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_recipient(&mut self, name: &String, passphrase: SecretString) -> Result<(), Error> {
+        self.check_new_recipient_name(name)?;
+
+        self.recipients.push(KinRecipient {
+            name: name.clone(),
+            passphrase: passphrase,
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_recipient(&mut self, name: &String) -> Result<KinRecipient, Error> {
+        self.get_recipient(name)?;
+
+        // Every backup is unlocked by the holders who remain, minus the one
+        // holding it
+        let remaining_peers = self.recipients.len().saturating_sub(2);
+        if remaining_peers < self.threshold as usize {
+            bail!(
+                "removing \"{}\" would leave too few backup holders to meet the threshold of {}; add someone else first",
+                name,
+                self.threshold
+            );
+        }
+
+        let index = self
+            .recipients
+            .iter()
+            .position(|x| &x.name == name)
+            .unwrap();
+
+        Ok(self.recipients.remove(index))
+    }
+
+    pub fn rename_recipient(&mut self, name: &String, new_name: &String) -> Result<(), Error> {
+        self.get_recipient(name)?;
+
+        // Allow fixing the capitalization of a name
+        if !same_name(name, new_name) {
+            self.check_new_recipient_name(new_name)?;
+        } else {
+            check_recipient_name(new_name)?;
+        }
+
+        for recip in self.recipients.iter_mut().filter(|x| &x.name == name) {
+            recip.name = new_name.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Replaces a recipient's passphrase, e.g. because it was exposed
+    pub fn set_passphrase(&mut self, name: &String, passphrase: SecretString) -> Result<(), Error> {
+        self.get_recipient(name)?;
+
+        let recip = self
+            .recipients
+            .iter_mut()
+            .find(|x| &x.name == name)
+            .unwrap();
+        recip.passphrase = passphrase;
+
+        Ok(())
+    }
+
+    fn check_new_recipient_name(&self, name: &String) -> Result<(), Error> {
+        check_recipient_name(name)?;
+
+        if let Some(existing) = self.recipients.iter().find(|x| same_name(&x.name, name)) {
+            bail!("there is already a recipient named \"{}\"", existing.name);
+        }
+
+        Ok(())
+    }
+
     pub fn master_key(&self) -> Result<MasterKey, Error> {
         MasterKey::decode_base64(&self.master_key)
     }
+
+    pub fn master_key_generation(&self) -> u32 {
+        self.master_key_generation
+    }
+
+    pub fn retired_master_keys(&self) -> &Vec<RetiredMasterKey> {
+        &self.retired_master_keys
+    }
+
+    /// Which generation `key` belongs to, if it's one of this project's
+    /// master keys
+    pub fn master_key_generation_of(&self, key: &MasterKey) -> Result<Option<u32>, Error> {
+        if key == &self.master_key()? {
+            return Ok(Some(self.master_key_generation));
+        }
+
+        for retired in self.retired_master_keys.iter() {
+            if key == &retired.master_key()? {
+                return Ok(Some(retired.generation));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Replaces the master key with a new one, e.g. because a package was
+    /// stolen. Packages compiled afterward can't be opened with the old key.
+    pub fn rekey(&mut self) -> Result<(), Error> {
+        let new_key = MasterKey::new().encode_base64();
+        let old_key = std::mem::replace(&mut self.master_key, new_key);
+
+        self.retired_master_keys.push(RetiredMasterKey {
+            generation: self.master_key_generation,
+            master_key: old_key,
+            retired: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        });
+        self.master_key_generation += 1;
+
+        Ok(())
+    }
+
+    pub fn signing_key(&self) -> Result<Option<SigningKey>, Error> {
+        match &self.signing_key {
+            Some(key) => Ok(Some(SigningKey::decode_base64(key)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn generate_signing_key(&mut self) {
+        self.signing_key = Some(SigningKey::new().encode_base64());
+    }
+}
+
+/// Serializes `value` as JSON straight into secure memory. It's serialized
+/// twice, first only to measure it, so no copy is left in a buffer that was
+/// outgrown.
+fn to_secret_json<T: Serialize>(value: &T) -> Result<SecretBytes, Error> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer_pretty(&mut counter, value)?;
+
+    let mut serialized = SecretBytes::new(counter.0);
+    serde_json::to_writer_pretty(serialized.as_mut_slice(), value)?;
+    Ok(serialized)
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The owner runs Kin on their own (presumably capable) computer every time
+/// they touch the project, so this favors responsiveness over the
+/// "sensitive" profile used by default for backup holders.
+const OWNER_KDF_PROFILE: KdfProfile = KdfProfile::Moderate;
+
+/// On-disk layout of a settings file protected by an owner passphrase
+#[derive(Serialize, Deserialize)]
+struct EncryptedSettingsFile {
+    encrypted_settings: EncryptedSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedSettings {
+    data: String,
+    passphrase_salt: String,
+    nonce: String,
+    kdf_algorithm: String,
+    kdf_opslimit: u64,
+    kdf_memlimit: u64,
+}
+
+fn default_threshold() -> u8 {
+    1
+}
+
+/// Projects created before the master key could be replaced are still on
+/// their first one
+pub fn first_key_generation() -> u32 {
+    1
+}
+
+fn check_recipient_name(name: &String) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        bail!("recipient names can't be blank");
+    }
+
+    if name.trim() != name {
+        bail!("recipient names can't start or end with spaces");
+    }
+
+    Ok(())
+}
+
+/// Names only differing by case would be too easy to mix up in readmes and on
+/// the command line
+fn same_name(a: &String, b: &String) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::settings_named as settings;
+    use crate::test_support::TempDir;
+
+    fn names(settings: &KinSettings) -> Vec<&str> {
+        settings
+            .recipients
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn recipient_names_must_be_unique() {
+        let mut settings = settings(&["Alice", "Bob"], 1);
+
+        let passphrase = SecretString::copy_from("x");
+        assert!(settings
+            .add_recipient(&String::from("alice"), passphrase.clone())
+            .is_err());
+        assert!(settings
+            .add_recipient(&String::from(" "), passphrase.clone())
+            .is_err());
+        assert!(settings
+            .add_recipient(&String::from("Chuck "), passphrase.clone())
+            .is_err());
+        settings
+            .add_recipient(&String::from("Chuck"), passphrase)
+            .unwrap();
+        assert_eq!(names(&settings), vec!["Alice", "Bob", "Chuck"]);
+
+        assert!(settings
+            .rename_recipient(&String::from("Bob"), &String::from("CHUCK"))
+            .is_err());
+        settings
+            .rename_recipient(&String::from("Bob"), &String::from("bob"))
+            .unwrap();
+        settings
+            .rename_recipient(&String::from("bob"), &String::from("Robert"))
+            .unwrap();
+        assert_eq!(names(&settings), vec!["Alice", "Robert", "Chuck"]);
+
+        let list = |names: &[&str]| names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert!(KinSettings::check_recipient_names(&list(&["Alice", "Bob"])).is_ok());
+        assert!(KinSettings::check_recipient_names(&list(&["Alice", "ALICE"])).is_err());
+        assert!(KinSettings::check_recipient_names(&list(&["Alice", ""])).is_err());
+    }
+
+    #[test]
+    fn rekey_retires_the_old_master_key() {
+        let mut settings = settings(&["Alice", "Bob"], 1);
+        let first_key = settings.master_key().unwrap();
+        assert_eq!(settings.master_key_generation(), 1);
+
+        settings.rekey().unwrap();
+        settings.rekey().unwrap();
+
+        assert_eq!(settings.master_key_generation(), 3);
+        assert!(settings.master_key().unwrap() != first_key);
+        assert_eq!(settings.retired_master_keys().len(), 2);
+        assert_eq!(
+            settings.master_key_generation_of(&first_key).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            settings
+                .master_key_generation_of(&settings.master_key().unwrap())
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            settings
+                .master_key_generation_of(&MasterKey::new())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn removing_recipients_keeps_threshold_reachable() {
+        let mut settings = settings(&["Alice", "Bob", "Chuck"], 1);
+
+        assert!(settings.remove_recipient(&String::from("Dana")).is_err());
+
+        let removed = settings.remove_recipient(&String::from("Bob")).unwrap();
+        assert_eq!(removed.name, "Bob");
+        assert_eq!(names(&settings), vec!["Alice", "Chuck"]);
+
+        // Whoever was left couldn't unlock the other's backup
+        assert!(settings.remove_recipient(&String::from("Alice")).is_err());
+
+        let mut settings = self::settings(&["Alice", "Bob", "Chuck"], 2);
+        assert!(settings.remove_recipient(&String::from("Chuck")).is_err());
+    }
+
+    #[test]
+    fn settings_round_trip_under_an_owner_passphrase() {
+        let temp = TempDir::new();
+        let path = temp.path().join("config.json");
+        let written = settings(&["Alice", "Bob"], 1);
+        let passphrase = SecretString::copy_from("owner passphrase");
+
+        written.write(&path, Some(&passphrase)).unwrap();
+        assert!(KinSettings::is_encrypted(&path).unwrap());
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(!contents.contains("Alice"));
+
+        let read = KinSettings::read(&path, Some(&passphrase)).unwrap();
+        assert_eq!(names(&read), vec!["Alice", "Bob"]);
+        assert!(read.master_key().unwrap() == written.master_key().unwrap());
+
+        let wrong = SecretString::copy_from("not the owner passphrase");
+        let error = KinSettings::read(&path, Some(&wrong)).err().unwrap();
+        assert_eq!(error.to_string(), "wrong owner passphrase");
+        assert!(KinSettings::read(&path, None).is_err());
+    }
+
+    #[test]
+    fn settings_without_an_owner_passphrase_are_plain_json() {
+        let temp = TempDir::new();
+        let path = temp.path().join("config.json");
+
+        settings(&["Alice", "Bob"], 1).write(&path, None).unwrap();
+        assert!(!KinSettings::is_encrypted(&path).unwrap());
+        assert_eq!(
+            names(&KinSettings::read(&path, None).unwrap()),
+            vec!["Alice", "Bob"]
+        );
+
+        // Settings written before there was anything but an owner, a master
+        // key and recipients
+        let mut legacy = serde_json::to_value(settings(&["Alice", "Bob"], 1)).unwrap();
+        let legacy = legacy.as_object_mut().unwrap();
+        legacy.retain(|key, _| ["owner", "master_key", "recipients"].contains(&key.as_str()));
+        std::fs::write(&path, serde_json::to_string(legacy).unwrap()).unwrap();
+
+        let read = KinSettings::read(&path, None).unwrap();
+        assert_eq!(names(&read), vec!["Alice", "Bob"]);
+        assert_eq!(read.master_key_generation(), 1);
+        assert_eq!(read.threshold, 1);
+        assert!(read.signing_key().unwrap().is_none());
+    }
 }
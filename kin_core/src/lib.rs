@@ -1,10 +1,17 @@
-pub use self::backuppackage::BackupPackage;
+pub use self::backuppackage::{ArchiveBinding, BackupPackage};
 pub use self::cmdline::parse as parse_cmdline;
-pub use self::cmdline::{CliResult, CompileArgs, DecryptArgs, InitArgs, SubCommand};
-pub use self::kinproject::KinProject;
-pub use self::kinsettings::{KinRecipient, KinSettings};
+pub use self::cmdline::{CliResult, CompileArgs, DecryptArgs, InitArgs, SubCommand, VerifyArgs};
+pub use self::cmdline::{DoctorArgs, RekeyArgs, StatusArgs};
+pub use self::cmdline::{
+    RecipientAddArgs, RecipientArgs, RecipientCommand, RecipientListArgs, RecipientRemoveArgs,
+    RecipientRenameArgs, RecipientRotateArgs,
+};
+pub use self::kinproject::{KinProject, ProjectFile};
+pub use self::kinsettings::{KinRecipient, KinSettings, RetiredMasterKey};
 pub use self::libsodium::init as libsodium_init;
-pub use self::libsodium::EncryptedMasterKey;
+pub use self::libsodium::{EncryptedMasterKey, KdfProfile, SecretString};
+pub use self::manifest::Manifest;
+pub use self::registry::{IssuedPackage, PackageRegistry};
 pub use failure::bail;
 pub use failure::Error;
 pub use log::info;
@@ -14,8 +21,14 @@ pub mod fsutil;
 pub mod libsodium;
 pub mod templating;
 pub mod ui;
+pub mod validation;
+
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 mod backuppackage;
 mod cmdline;
 mod kinproject;
 mod kinsettings;
+mod manifest;
+mod registry;
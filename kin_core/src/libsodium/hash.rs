@@ -0,0 +1,68 @@
+use libsodium_sys;
+use std::io::Read;
+
+// SHA-256 is used (rather than libsodium's default BLAKE2b) so backup holders
+// can check file hashes with tools like `sha256sum`.
+// https://download.libsodium.org/doc/advanced/sha-2_hash_function
+
+const SHA256_SIZE: usize = libsodium_sys::crypto_hash_sha256_BYTES as usize;
+const READ_BUF_SIZE: usize = 65536; // 64 KiB
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hash = vec![0; SHA256_SIZE];
+
+    unsafe {
+        libsodium_sys::crypto_hash_sha256(hash.as_mut_ptr(), data.as_ptr(), data.len() as u64);
+    }
+
+    hash
+}
+
+/// Hashes everything `input` has to give
+pub fn sha256_reader(input: &mut Read) -> Result<Vec<u8>, std::io::Error> {
+    let mut hash = vec![0; SHA256_SIZE];
+    let mut buf = vec![0; READ_BUF_SIZE];
+
+    unsafe {
+        let mut state: libsodium_sys::crypto_hash_sha256_state = std::mem::zeroed();
+        libsodium_sys::crypto_hash_sha256_init(&mut state);
+
+        loop {
+            let count = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            libsodium_sys::crypto_hash_sha256_update(&mut state, buf.as_ptr(), count as u64);
+        }
+
+        libsodium_sys::crypto_hash_sha256_final(&mut state, hash.as_mut_ptr());
+    }
+
+    Ok(hash)
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        crate::libsodium::init().unwrap();
+
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let long: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+        assert_eq!(sha256_reader(&mut &long[..]).unwrap(), sha256(&long));
+    }
+}
@@ -0,0 +1,131 @@
+use failure::bail;
+use std::io::{Read, Write};
+
+// Every private.kin archive starts with this fixed-size header, followed by
+// the libsodium secretstream header and the encrypted chunks. All integers
+// are little-endian.
+//
+//   offset  size  field
+//   0       8     magic bytes ("KINARCHV")
+//   8       2     format version
+//   10      1     cipher suite identifier
+//   11      1     key derivation function identifier
+//   12      4     plaintext chunk size in bytes
+//
+// Backups may sit in a drawer for a decade, so anything that changes how an
+// archive is laid out or encrypted must bump FORMAT_VERSION.
+//
+//   version  changes
+//   1        initial format
+//   2        every chunk authenticates the package's associated data
+
+const MAGIC: [u8; 8] = *b"KINARCHV";
+pub const HEADER_SIZE: usize = 16;
+
+pub const FORMAT_VERSION: u16 = 2;
+
+/// The first format version that binds an archive to its package
+pub const FIRST_VERSION_WITH_AD: u16 = 2;
+
+/// libsodium's crypto_secretstream_xchacha20poly1305
+pub const CIPHER_XCHACHA20POLY1305_SECRETSTREAM: u8 = 1;
+
+/// libsodium's crypto_pwhash with Argon2id v1.3
+pub const KDF_ARGON2ID13: u8 = 1;
+
+pub const MIN_CHUNK_SIZE: u32 = 1024; // 1 KiB
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024; // 16 MiB
+
+pub struct ArchiveHeader {
+    pub version: u16,
+    pub cipher: u8,
+    pub kdf: u8,
+    pub chunk_size: u32,
+}
+
+impl ArchiveHeader {
+    pub fn new(chunk_size: u32) -> ArchiveHeader {
+        ArchiveHeader {
+            version: FORMAT_VERSION,
+            cipher: CIPHER_XCHACHA20POLY1305_SECRETSTREAM,
+            kdf: KDF_ARGON2ID13,
+            chunk_size: chunk_size,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10] = self.cipher;
+        bytes[11] = self.kdf;
+        bytes[12..16].copy_from_slice(&self.chunk_size.to_le_bytes());
+        bytes
+    }
+
+    pub fn write(&self, output: &mut Write) -> Result<(), failure::Error> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Reads and validates an archive header. Fails if the data isn't a Kin
+    /// archive, or if it was written in a format this version of Kin doesn't
+    /// understand.
+    pub fn read(input: &mut Read) -> Result<ArchiveHeader, failure::Error> {
+        let mut bytes: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        if let Err(e) = input.read_exact(&mut bytes) {
+            bail!("unable to read archive header: {}", e);
+        }
+
+        if bytes[0..8] != MAGIC {
+            bail!("not a Kin private archive (unrecognized header)");
+        }
+
+        let mut version: [u8; 2] = [0; 2];
+        version.copy_from_slice(&bytes[8..10]);
+        let mut chunk_size: [u8; 4] = [0; 4];
+        chunk_size.copy_from_slice(&bytes[12..16]);
+
+        let header = ArchiveHeader {
+            version: u16::from_le_bytes(version),
+            cipher: bytes[10],
+            kdf: bytes[11],
+            chunk_size: u32::from_le_bytes(chunk_size),
+        };
+
+        header.validate()?;
+        Ok(header)
+    }
+
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if self.version == 0 || self.version > FORMAT_VERSION {
+            bail!(
+                "archive format version {} is not supported (this version of Kin supports up to version {}); try a newer version of Kin",
+                self.version,
+                FORMAT_VERSION
+            );
+        }
+
+        if self.cipher != CIPHER_XCHACHA20POLY1305_SECRETSTREAM {
+            bail!("archive uses an unknown cipher suite ({})", self.cipher);
+        }
+
+        if self.kdf != KDF_ARGON2ID13 {
+            bail!(
+                "archive uses an unknown key derivation function ({})",
+                self.kdf
+            );
+        }
+
+        if self.chunk_size < MIN_CHUNK_SIZE || self.chunk_size > MAX_CHUNK_SIZE {
+            bail!(
+                "archive chunk size {} is out of range ({} to {} bytes)",
+                self.chunk_size,
+                MIN_CHUNK_SIZE,
+                MAX_CHUNK_SIZE
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,30 +1,42 @@
-use super::passphrase::{PassphraseDerivedKey, PassphraseSalt};
+use super::passphrase::{KdfParams, PassphraseDerivedKey, PassphraseSalt};
+use super::secret::{SecretBytes, SecretString};
+use super::secretbox;
+use super::shamir;
+use super::shamir::KeyShare;
 use failure::{bail, format_err};
 use libsodium_sys;
 
-// authenticated encryption docs:
-// https://download.libsodium.org/doc/secret-key_cryptography/authenticated_encryption
-
 pub struct MasterKey {
-    data: Vec<u8>,
+    data: SecretBytes,
+}
+
+impl PartialEq for MasterKey {
+    fn eq(&self, other: &MasterKey) -> bool {
+        self.data == other.data
+    }
 }
 
+/// A key slot: either the whole master key, or one share of it, encrypted
+/// with a passphrase-derived key.
 pub struct EncryptedMasterKey {
     encrypted_data: Vec<u8>,
     salt: PassphraseSalt,
+    kdf: KdfParams,
     nonce: Vec<u8>,
 }
 
 pub const MASTER_KEY_SIZE: usize =
     libsodium_sys::crypto_secretstream_xchacha20poly1305_KEYBYTES as usize;
 const ENCRYPTED_MASTER_KEY_SIZE: usize = MASTER_KEY_SIZE + SECRETBOX_MAC_SIZE;
+const KEY_SHARE_SIZE: usize = MASTER_KEY_SIZE + 1;
+const ENCRYPTED_KEY_SHARE_SIZE: usize = KEY_SHARE_SIZE + SECRETBOX_MAC_SIZE;
 const SECRETBOX_MAC_SIZE: usize = libsodium_sys::crypto_secretbox_MACBYTES as usize;
 const SECRETBOX_NONCE_SIZE: usize = libsodium_sys::crypto_secretbox_NONCEBYTES as usize;
 
 impl MasterKey {
     pub fn new() -> MasterKey {
         let mut key = MasterKey {
-            data: vec![0; MASTER_KEY_SIZE],
+            data: SecretBytes::new(MASTER_KEY_SIZE),
         };
 
         unsafe {
@@ -34,8 +46,8 @@ impl MasterKey {
         key
     }
 
-    pub fn decode_base64(base64_contents: &String) -> Result<MasterKey, failure::Error> {
-        let decoded = base64::decode(base64_contents)?;
+    pub fn decode_base64(base64_contents: &SecretString) -> Result<MasterKey, failure::Error> {
+        let decoded = SecretBytes::from_vec(base64::decode(base64_contents.as_bytes())?);
 
         if decoded.len() != MASTER_KEY_SIZE {
             return Err(format_err!(
@@ -48,41 +60,52 @@ impl MasterKey {
         Ok(MasterKey { data: decoded })
     }
 
-    pub fn encode_base64(&self) -> String {
-        base64::encode(&self.data)
+    pub fn encode_base64(&self) -> SecretString {
+        SecretString::from(base64::encode(self.data.as_slice()))
     }
 
-    pub fn encrypt(&self, passphrase: &String) -> Result<EncryptedMasterKey, failure::Error> {
-        let key = PassphraseDerivedKey::generate(passphrase)?;
-
-        let mut nonce: [u8; SECRETBOX_NONCE_SIZE] = [0; SECRETBOX_NONCE_SIZE];
-        super::randombytes_into(&mut nonce);
-        let nonce = nonce; // No longer mutable
-
-        let mut cipher_text: [u8; ENCRYPTED_MASTER_KEY_SIZE] = [0; ENCRYPTED_MASTER_KEY_SIZE];
+    /// Wraps the master key once for each passphrase. All of the resulting
+    /// key slots share one salt and one set of KDF parameters, so decrypting
+    /// only ever needs one (slow) key derivation per passphrase attempt, no
+    /// matter how many slots there are. The slots come back in random order,
+    /// and nothing in them says which passphrase they belong to.
+    pub fn encrypt(
+        &self,
+        passphrases: &Vec<&SecretString>,
+        kdf: &KdfParams,
+    ) -> Result<Vec<EncryptedMasterKey>, failure::Error> {
+        let plaintexts = passphrases.iter().map(|_| self.data.clone()).collect();
+        encrypt_slots(plaintexts, passphrases, kdf)
+    }
 
-        let result;
-        unsafe {
-            result = libsodium_sys::crypto_secretbox_easy(
-                cipher_text.as_mut_ptr(),
-                self.data.as_ptr(),
-                MASTER_KEY_SIZE as u64,
-                nonce.as_ptr(),
-                key.as_ptr(),
-            );
+    /// Splits the master key into one share per passphrase, any `threshold`
+    /// of which can rebuild it, and wraps each share with its passphrase.
+    /// Slots are laid out the same way as with `encrypt`.
+    pub fn encrypt_shares(
+        &self,
+        passphrases: &Vec<&SecretString>,
+        threshold: u8,
+        kdf: &KdfParams,
+    ) -> Result<Vec<EncryptedMasterKey>, failure::Error> {
+        if passphrases.len() > u8::max_value() as usize {
+            bail!("Too many passphrases to split a key between.");
         }
 
-        if result != 0 {
-            bail!("Error while encrypting.");
-        }
+        let shares = shamir::split(self.data.as_slice(), threshold, passphrases.len() as u8)?;
+        let plaintexts = shares.iter().map(|x| x.to_bytes()).collect();
+        encrypt_slots(plaintexts, passphrases, kdf)
+    }
 
-        let cipher_text = cipher_text; // No longer mutable
+    /// Rebuilds the master key from shares unlocked with
+    /// `EncryptedMasterKey::decrypt_share_any`. There must be at least as
+    /// many shares as the threshold the key was split with.
+    pub fn from_shares(shares: &Vec<KeyShare>) -> Result<MasterKey, failure::Error> {
+        let data = shamir::combine(shares)?;
+        if data.len() != MASTER_KEY_SIZE {
+            bail!("Key shares are an invalid length.");
+        }
 
-        Ok(EncryptedMasterKey {
-            salt: key.salt,
-            encrypted_data: cipher_text.to_vec(),
-            nonce: nonce.to_vec(),
-        })
+        Ok(MasterKey { data: data })
     }
 
     pub fn as_ptr(&self) -> *const u8 {
@@ -95,9 +118,12 @@ impl EncryptedMasterKey {
         encrypted_data: &String,
         salt: &String,
         nonce: &String,
+        kdf: KdfParams,
     ) -> Result<EncryptedMasterKey, failure::Error> {
         let encrypted_data = base64::decode(&encrypted_data)?;
-        if encrypted_data.len() != ENCRYPTED_MASTER_KEY_SIZE {
+        if encrypted_data.len() != ENCRYPTED_MASTER_KEY_SIZE
+            && encrypted_data.len() != ENCRYPTED_KEY_SHARE_SIZE
+        {
             bail!("Invalid encrypted key data.");
         }
 
@@ -111,6 +137,7 @@ impl EncryptedMasterKey {
         Ok(EncryptedMasterKey {
             encrypted_data: encrypted_data,
             salt: salt,
+            kdf: kdf,
             nonce: nonce,
         })
     }
@@ -119,6 +146,10 @@ impl EncryptedMasterKey {
         self.salt.encode_base64()
     }
 
+    pub fn kdf(&self) -> KdfParams {
+        self.kdf
+    }
+
     pub fn data(&self) -> String {
         base64::encode(&self.encrypted_data)
     }
@@ -127,28 +158,196 @@ impl EncryptedMasterKey {
         base64::encode(&self.nonce)
     }
 
-    pub fn decrypt(&self, passphrase: &String) -> Result<MasterKey, failure::Error> {
-        let key = PassphraseDerivedKey::from(passphrase, &self.salt)?;
+    /// Returns true if this slot holds a share of the master key rather than
+    /// the whole key.
+    pub fn is_share(&self) -> bool {
+        self.encrypted_data.len() == ENCRYPTED_KEY_SHARE_SIZE
+    }
 
-        let mut plain_text: [u8; MASTER_KEY_SIZE] = [0; MASTER_KEY_SIZE];
+    /// Tries the passphrase against every slot holding the whole master key.
+    pub fn decrypt_any(
+        slots: &Vec<EncryptedMasterKey>,
+        passphrase: &SecretString,
+    ) -> Result<MasterKey, failure::Error> {
+        let candidates = slots.iter().filter(|x| !x.is_share()).collect();
+        match open_any(&candidates, passphrase)? {
+            Some(data) => Ok(MasterKey { data: data }),
+            None => bail!("Unable to decrypt master key"), // TODO: Be more specific
+        }
+    }
 
-        let result;
-        unsafe {
-            result = libsodium_sys::crypto_secretbox_open_easy(
-                plain_text.as_mut_ptr(),
-                self.encrypted_data.as_ptr(),
-                self.encrypted_data.len() as u64,
-                self.nonce.as_ptr(),
-                key.as_ptr(),
-            );
+    /// Tries the passphrase against every slot holding a share of the master
+    /// key. Returns `None` if it doesn't unlock any of them; errors are for
+    /// everything else, like running out of memory deriving the key.
+    pub fn decrypt_share_any(
+        slots: &Vec<EncryptedMasterKey>,
+        passphrase: &SecretString,
+    ) -> Result<Option<KeyShare>, failure::Error> {
+        let candidates = slots.iter().filter(|x| x.is_share()).collect();
+        match open_any(&candidates, passphrase)? {
+            Some(data) => Ok(Some(KeyShare::from_bytes(data.as_slice())?)),
+            None => Ok(None),
         }
+    }
 
-        if result != 0 {
-            bail!("Unable to decrypt."); // TODO: Be more specific
+    pub fn decrypt(&self, passphrase: &SecretString) -> Result<MasterKey, failure::Error> {
+        if self.is_share() {
+            bail!("This key slot only holds a share of the master key.");
         }
 
-        Ok(MasterKey {
-            data: plain_text.to_vec(),
-        })
+        let key = PassphraseDerivedKey::from(passphrase, &self.salt, &self.kdf)?;
+        let data = self.decrypt_with(&key)?;
+        Ok(MasterKey { data: data })
+    }
+
+    fn decrypt_with(&self, key: &PassphraseDerivedKey) -> Result<SecretBytes, failure::Error> {
+        secretbox::open(&self.encrypted_data, &self.nonce, key)
+    }
+
+    fn shares_derived_key(&self, other: &EncryptedMasterKey) -> bool {
+        self.salt == other.salt && self.kdf == other.kdf
+    }
+}
+
+/// Encrypts each plaintext with the corresponding passphrase, using one salt
+/// for all of them. See `MasterKey::encrypt`.
+fn encrypt_slots(
+    plaintexts: Vec<SecretBytes>,
+    passphrases: &Vec<&SecretString>,
+    kdf: &KdfParams,
+) -> Result<Vec<EncryptedMasterKey>, failure::Error> {
+    let salt = PassphraseSalt::generate();
+
+    let mut slots = Vec::new();
+    for (plaintext, passphrase) in plaintexts.iter().zip(passphrases.iter()) {
+        let key = PassphraseDerivedKey::from(passphrase, &salt, kdf)?;
+        slots.push(encrypt_slot(plaintext.as_slice(), &key)?);
+    }
+
+    shuffle(&mut slots);
+    Ok(slots)
+}
+
+fn encrypt_slot(
+    plaintext: &[u8],
+    key: &PassphraseDerivedKey,
+) -> Result<EncryptedMasterKey, failure::Error> {
+    let (encrypted_data, nonce) = secretbox::seal(plaintext, key)?;
+
+    Ok(EncryptedMasterKey {
+        salt: key.salt.clone(),
+        kdf: key.params,
+        encrypted_data: encrypted_data,
+        nonce: nonce,
+    })
+}
+
+/// Returns the contents of the first slot the passphrase unlocks. Slots that
+/// share a salt and KDF parameters share a derived key, so the key derivation
+/// function runs once per distinct (salt, parameters) pair rather than once
+/// per slot.
+fn open_any(
+    slots: &Vec<&EncryptedMasterKey>,
+    passphrase: &SecretString,
+) -> Result<Option<SecretBytes>, failure::Error> {
+    for (i, slot) in slots.iter().enumerate() {
+        if slots[0..i].iter().any(|x| x.shares_derived_key(slot)) {
+            continue; // Already tried this derived key
+        }
+
+        let key = PassphraseDerivedKey::from(passphrase, &slot.salt, &slot.kdf)?;
+
+        for candidate in slots[i..].iter().filter(|x| x.shares_derived_key(slot)) {
+            match candidate.decrypt_with(&key) {
+                Ok(data) => return Ok(Some(data)),
+                Err(_) => continue, // Expected; check the next slot
+            };
+        }
+    }
+
+    Ok(None)
+}
+
+/// Fisher-Yates shuffle using libsodium's random number generator
+fn shuffle<T>(items: &mut Vec<T>) {
+    for i in (1..items.len()).rev() {
+        let j = unsafe { libsodium_sys::randombytes_uniform(i as u32 + 1) } as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::passphrase::KdfProfile;
+    use super::*;
+
+    #[test]
+    fn every_passphrase_unlocks_a_slot() {
+        crate::libsodium::init().unwrap();
+
+        let master_key = MasterKey::new();
+        let passphrases = vec![
+            SecretString::copy_from("correct horse"),
+            SecretString::copy_from("battery staple"),
+            SecretString::copy_from("tr0ub4dor&3"),
+        ];
+        let kdf = KdfProfile::Interactive.params();
+
+        let slots = master_key
+            .encrypt(&passphrases.iter().collect(), &kdf)
+            .unwrap();
+        assert_eq!(slots.len(), 3);
+        assert!(slots.iter().all(|x| x.shares_derived_key(&slots[0])));
+
+        for passphrase in passphrases.iter() {
+            let decrypted = EncryptedMasterKey::decrypt_any(&slots, passphrase).unwrap();
+            assert_eq!(
+                decrypted.encode_base64().as_str(),
+                master_key.encode_base64().as_str()
+            );
+        }
+
+        let wrong = SecretString::copy_from("wrong passphrase");
+        assert!(EncryptedMasterKey::decrypt_any(&slots, &wrong).is_err());
+    }
+
+    #[test]
+    fn two_of_three_shares_rebuild_the_key() {
+        crate::libsodium::init().unwrap();
+
+        let master_key = MasterKey::new();
+        let passphrases = vec![
+            SecretString::copy_from("correct horse"),
+            SecretString::copy_from("battery staple"),
+            SecretString::copy_from("tr0ub4dor&3"),
+        ];
+        let kdf = KdfProfile::Interactive.params();
+
+        let slots = master_key
+            .encrypt_shares(&passphrases.iter().collect(), 2, &kdf)
+            .unwrap();
+        assert!(slots.iter().all(|x| x.is_share()));
+        assert!(EncryptedMasterKey::decrypt_any(&slots, &passphrases[0]).is_err());
+
+        let shares = vec![
+            EncryptedMasterKey::decrypt_share_any(&slots, &passphrases[2])
+                .unwrap()
+                .unwrap(),
+            EncryptedMasterKey::decrypt_share_any(&slots, &passphrases[0])
+                .unwrap()
+                .unwrap(),
+        ];
+
+        let wrong = SecretString::copy_from("Tr0ub4dor&3");
+        assert!(EncryptedMasterKey::decrypt_share_any(&slots, &wrong)
+            .unwrap()
+            .is_none());
+
+        let rebuilt = MasterKey::from_shares(&shares).unwrap();
+        assert_eq!(
+            rebuilt.encode_base64().as_str(),
+            master_key.encode_base64().as_str()
+        );
     }
 }
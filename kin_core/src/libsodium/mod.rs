@@ -1,15 +1,30 @@
+pub use self::hash::{sha256, sha256_reader, to_hex};
 pub use self::masterkey::{EncryptedMasterKey, MasterKey};
-pub use self::streams::{decrypt, encrypt};
+pub use self::passphrase::{KdfParams, KdfProfile};
+pub use self::secret::{wipe, SecretBytes, SecretString};
+pub use self::secretbox::PassphraseBox;
+pub use self::shamir::KeyShare;
+pub use self::signing::{PublicKey, SigningKey};
+pub use self::streams::{
+    decrypt, encrypt, encrypt_with_chunk_size, Decryptor, Encryptor, DEFAULT_CHUNK_SIZE,
+};
 
+mod hash;
+mod header;
 mod masterkey;
 mod passphrase;
+mod secret;
+mod secretbox;
+mod shamir;
+mod signing;
 mod streams;
 
 pub fn init() -> Result<(), failure::Error> {
     unsafe {
         let result = libsodium_sys::sodium_init();
 
-        if result == 0 {
+        // 0 means success, 1 means libsodium was already initialized
+        if result == 0 || result == 1 {
             return Ok(());
         } else {
             return Err(failure::err_msg("error initializing libsodium"));
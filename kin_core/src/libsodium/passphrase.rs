@@ -1,23 +1,157 @@
-use failure::bail;
+use super::secret::{SecretBytes, SecretString};
+use failure::{bail, format_err};
 use libsodium_sys;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 // key derivation docs:
 // https://download.libsodium.org/doc/key_derivation
 
+#[derive(Clone, PartialEq)]
 pub struct PassphraseSalt {
     data: Vec<u8>,
 }
 
 pub struct PassphraseDerivedKey {
     pub salt: PassphraseSalt,
-    data: Vec<u8>,
+    pub params: KdfParams,
+    data: SecretBytes,
+}
+
+/// How much work (and memory) goes into turning a passphrase into a key.
+/// Stronger profiles make brute force attacks more expensive, but also need
+/// more RAM on whatever computer eventually decrypts the backup.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfProfile {
+    /// About 1 GiB of RAM
+    Sensitive,
+    /// About 256 MiB of RAM
+    Moderate,
+    /// About 64 MiB of RAM
+    Interactive,
+}
+
+/// The Argon2 parameters used to derive a particular key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    algorithm: i32,
+    opslimit: u64,
+    memlimit: usize,
 }
 
 const SECRETBOX_KEY_SIZE: usize = libsodium_sys::crypto_secretbox_KEYBYTES as usize;
 const SALT_SIZE: usize = libsodium_sys::crypto_pwhash_SALTBYTES as usize;
+const ARGON2ID13_NAME: &str = "argon2id13";
+
+impl KdfProfile {
+    pub fn params(&self) -> KdfParams {
+        let (opslimit, memlimit) = match self {
+            KdfProfile::Sensitive => (
+                libsodium_sys::crypto_pwhash_OPSLIMIT_SENSITIVE as u64,
+                libsodium_sys::crypto_pwhash_MEMLIMIT_SENSITIVE as usize,
+            ),
+            KdfProfile::Moderate => (
+                libsodium_sys::crypto_pwhash_OPSLIMIT_MODERATE as u64,
+                libsodium_sys::crypto_pwhash_MEMLIMIT_MODERATE as usize,
+            ),
+            KdfProfile::Interactive => (
+                libsodium_sys::crypto_pwhash_OPSLIMIT_INTERACTIVE as u64,
+                libsodium_sys::crypto_pwhash_MEMLIMIT_INTERACTIVE as usize,
+            ),
+        };
+
+        KdfParams {
+            algorithm: libsodium_sys::crypto_pwhash_ALG_ARGON2ID13 as i32,
+            opslimit: opslimit,
+            memlimit: memlimit,
+        }
+    }
+}
+
+impl Default for KdfProfile {
+    fn default() -> KdfProfile {
+        KdfProfile::Sensitive
+    }
+}
+
+impl FromStr for KdfProfile {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<KdfProfile, failure::Error> {
+        match s.to_lowercase().as_str() {
+            "sensitive" => Ok(KdfProfile::Sensitive),
+            "moderate" => Ok(KdfProfile::Moderate),
+            "interactive" => Ok(KdfProfile::Interactive),
+            _ => Err(format_err!(
+                "unknown key derivation profile \"{}\" (expected sensitive, moderate, or interactive)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KdfProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            KdfProfile::Sensitive => "sensitive",
+            KdfProfile::Moderate => "moderate",
+            KdfProfile::Interactive => "interactive",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl KdfParams {
+    pub fn new(
+        algorithm: &String,
+        opslimit: u64,
+        memlimit: u64,
+    ) -> Result<KdfParams, failure::Error> {
+        if algorithm != ARGON2ID13_NAME {
+            bail!("Unsupported key derivation algorithm \"{}\".", algorithm);
+        }
+
+        let (min_ops, max_ops, min_mem, max_mem);
+        unsafe {
+            min_ops = libsodium_sys::crypto_pwhash_opslimit_min() as u64;
+            max_ops = libsodium_sys::crypto_pwhash_opslimit_max() as u64;
+            min_mem = libsodium_sys::crypto_pwhash_memlimit_min() as u64;
+            max_mem = libsodium_sys::crypto_pwhash_memlimit_max() as u64;
+        }
+
+        if opslimit < min_ops || opslimit > max_ops {
+            bail!("Invalid key derivation opslimit ({}).", opslimit);
+        }
+
+        if memlimit < min_mem || memlimit > max_mem {
+            bail!("Invalid key derivation memlimit ({}).", memlimit);
+        }
+
+        Ok(KdfParams {
+            algorithm: libsodium_sys::crypto_pwhash_ALG_ARGON2ID13 as i32,
+            opslimit: opslimit,
+            memlimit: memlimit as usize,
+        })
+    }
+
+    pub fn algorithm(&self) -> String {
+        String::from(ARGON2ID13_NAME)
+    }
+
+    pub fn opslimit(&self) -> u64 {
+        self.opslimit
+    }
+
+    pub fn memlimit(&self) -> u64 {
+        self.memlimit as u64
+    }
+}
 
 impl PassphraseSalt {
-    fn generate() -> PassphraseSalt {
+    pub fn generate() -> PassphraseSalt {
         let mut buf: [u8; SALT_SIZE] = [0; SALT_SIZE];
         super::randombytes_into(&mut buf);
         PassphraseSalt { data: buf.to_vec() }
@@ -42,39 +176,39 @@ impl PassphraseSalt {
 }
 
 impl PassphraseDerivedKey {
-    pub fn generate(passphrase: &String) -> Result<PassphraseDerivedKey, failure::Error> {
-        let salt = PassphraseSalt::generate();
-        PassphraseDerivedKey::from(passphrase, &salt)
-    }
-
     pub fn from(
-        passphrase: &String,
+        passphrase: &SecretString,
         salt: &PassphraseSalt,
+        params: &KdfParams,
     ) -> Result<PassphraseDerivedKey, failure::Error> {
-        let c_passphrase = std::ffi::CString::new(passphrase.as_str())
-            .expect("Could not convert passphase to a CString");
-        let mut key: [u8; SECRETBOX_KEY_SIZE] = [0; SECRETBOX_KEY_SIZE];
+        let mut key = SecretBytes::new(SECRETBOX_KEY_SIZE);
 
+        // crypto_pwhash takes an explicit length, so the passphrase doesn't
+        // need to be copied into a NUL-terminated string first
         let result;
         unsafe {
             result = libsodium_sys::crypto_pwhash(
                 key.as_mut_ptr(),
                 SECRETBOX_KEY_SIZE as u64,
-                c_passphrase.as_ptr(),
-                c_passphrase.as_bytes().len() as u64,
+                passphrase.as_bytes().as_ptr() as *const _,
+                passphrase.len() as u64,
                 salt.as_ptr(),
-                libsodium_sys::crypto_pwhash_OPSLIMIT_SENSITIVE as u64,
-                libsodium_sys::crypto_pwhash_MEMLIMIT_SENSITIVE as usize,
-                libsodium_sys::crypto_pwhash_ALG_ARGON2ID13 as i32,
+                params.opslimit,
+                params.memlimit,
+                params.algorithm,
             );
         }
 
         if result != 0 {
-            bail!("Ran out of memory during key derivation.");
+            bail!(
+                "Ran out of memory during key derivation (this key needs {} MiB of RAM).",
+                params.memlimit / (1024 * 1024)
+            );
         }
 
         Ok(PassphraseDerivedKey {
-            data: key.to_vec(),
+            params: *params,
+            data: key,
             salt: PassphraseSalt {
                 data: salt.data.clone(),
             },
@@ -0,0 +1,212 @@
+use libsodium_sys;
+use serde::de::{Deserialize, Deserializer, Error as DeError, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::slice;
+
+// secure memory docs:
+// https://download.libsodium.org/doc/memory_management
+//
+// Everything allocated here lives in memory from sodium_malloc: it sits
+// between guard pages, is locked so it won't be swapped to disk, and is
+// wiped when it's dropped. libsodium must be initialized first.
+
+/// A fixed-size buffer for keys and other secrets
+pub struct SecretBytes {
+    ptr: *mut u8,
+    len: usize,
+}
+
+/// A UTF-8 string (usually a passphrase) kept in secure memory
+pub struct SecretString {
+    bytes: SecretBytes,
+}
+
+impl SecretBytes {
+    /// Allocates `len` zeroed bytes
+    pub fn new(len: usize) -> SecretBytes {
+        // sodium_malloc doesn't promise anything useful for zero-sized
+        // allocations, so always ask for at least one byte.
+        let ptr = unsafe { libsodium_sys::sodium_malloc(len.max(1)) } as *mut u8;
+        if ptr.is_null() {
+            panic!("unable to allocate secure memory");
+        }
+
+        let mut bytes = SecretBytes { ptr: ptr, len: len };
+        for b in bytes.as_mut_slice().iter_mut() {
+            *b = 0;
+        }
+
+        bytes
+    }
+
+    pub fn from_slice(data: &[u8]) -> SecretBytes {
+        let mut bytes = SecretBytes::new(data.len());
+        bytes.as_mut_slice().copy_from_slice(data);
+        bytes
+    }
+
+    /// Moves the contents of `data` into secure memory and wipes the original
+    pub fn from_vec(mut data: Vec<u8>) -> SecretBytes {
+        let bytes = SecretBytes::from_slice(&data);
+        wipe(&mut data);
+        bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+// The buffer is uniquely owned, just like a Vec
+unsafe impl Send for SecretBytes {}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // sodium_free wipes the memory before releasing it
+        unsafe {
+            libsodium_sys::sodium_free(self.ptr as *mut _);
+        }
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::from_slice(self.as_slice())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &SecretBytes) -> bool {
+        self.len == other.len
+            && unsafe {
+                libsodium_sys::sodium_memcmp(self.ptr as *const _, other.ptr as *const _, self.len)
+                    == 0
+            }
+    }
+}
+
+impl SecretString {
+    /// Moves `s` into secure memory and wipes the original
+    pub fn from(s: String) -> SecretString {
+        SecretString {
+            bytes: SecretBytes::from_vec(s.into_bytes()),
+        }
+    }
+
+    pub fn copy_from(s: &str) -> SecretString {
+        SecretString {
+            bytes: SecretBytes::from_slice(s.as_bytes()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from valid UTF-8
+        unsafe { std::str::from_utf8_unchecked(self.bytes.as_slice()) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> SecretString {
+        SecretString {
+            bytes: self.bytes.clone(),
+        }
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SecretString, D::Error> {
+        deserializer.deserialize_str(SecretStringVisitor)
+    }
+}
+
+struct SecretStringVisitor;
+
+impl<'de> Visitor<'de> for SecretStringVisitor {
+    type Value = SecretString;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string")
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<SecretString, E> {
+        Ok(SecretString::copy_from(v))
+    }
+
+    fn visit_string<E: DeError>(self, v: String) -> Result<SecretString, E> {
+        Ok(SecretString::from(v))
+    }
+}
+
+/// Zeroes a buffer's whole allocation in a way the compiler won't optimize
+/// away
+pub fn wipe(data: &mut Vec<u8>) {
+    unsafe {
+        libsodium_sys::sodium_memzero(data.as_mut_ptr() as *mut _, data.capacity());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn secret_string_round_trip() {
+        crate::libsodium::init().unwrap();
+
+        let passphrase = SecretString::from(String::from("correct horse battery staple"));
+        assert_eq!(passphrase.as_str(), "correct horse battery staple");
+        assert!(passphrase == passphrase.clone());
+        assert!(passphrase != SecretString::copy_from("correct horse battery"));
+
+        let json = serde_json::to_string(&passphrase).unwrap();
+        let parsed: SecretString = serde_json::from_str(&json).unwrap();
+        assert!(parsed == passphrase);
+    }
+
+    #[test]
+    fn empty_buffer() {
+        crate::libsodium::init().unwrap();
+
+        let bytes = SecretBytes::new(0);
+        assert_eq!(bytes.len(), 0);
+        assert!(bytes.as_slice().is_empty());
+    }
+}
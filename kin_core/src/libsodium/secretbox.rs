@@ -0,0 +1,143 @@
+use super::passphrase::{KdfParams, PassphraseDerivedKey, PassphraseSalt};
+use super::secret::{SecretBytes, SecretString};
+use failure::bail;
+use libsodium_sys;
+
+// authenticated encryption docs:
+// https://download.libsodium.org/doc/secret-key_cryptography/authenticated_encryption
+
+const SECRETBOX_MAC_SIZE: usize = libsodium_sys::crypto_secretbox_MACBYTES as usize;
+const SECRETBOX_NONCE_SIZE: usize = libsodium_sys::crypto_secretbox_NONCEBYTES as usize;
+
+/// Arbitrary data encrypted with a key derived from a passphrase
+pub struct PassphraseBox {
+    encrypted_data: Vec<u8>,
+    salt: PassphraseSalt,
+    kdf: KdfParams,
+    nonce: Vec<u8>,
+}
+
+impl PassphraseBox {
+    pub fn seal(
+        plaintext: &[u8],
+        passphrase: &SecretString,
+        kdf: &KdfParams,
+    ) -> Result<PassphraseBox, failure::Error> {
+        let salt = PassphraseSalt::generate();
+        let key = PassphraseDerivedKey::from(passphrase, &salt, kdf)?;
+        let (encrypted_data, nonce) = seal(plaintext, &key)?;
+
+        Ok(PassphraseBox {
+            encrypted_data: encrypted_data,
+            salt: salt,
+            kdf: *kdf,
+            nonce: nonce,
+        })
+    }
+
+    pub fn new(
+        encrypted_data: &String,
+        salt: &String,
+        nonce: &String,
+        kdf: KdfParams,
+    ) -> Result<PassphraseBox, failure::Error> {
+        let encrypted_data = base64::decode(&encrypted_data)?;
+        if encrypted_data.len() < SECRETBOX_MAC_SIZE {
+            bail!("Invalid encrypted data.");
+        }
+
+        let salt = PassphraseSalt::from(&salt)?;
+
+        let nonce = base64::decode(&nonce)?;
+        if nonce.len() != SECRETBOX_NONCE_SIZE {
+            bail!("Invalid nonce data.");
+        }
+
+        Ok(PassphraseBox {
+            encrypted_data: encrypted_data,
+            salt: salt,
+            kdf: kdf,
+            nonce: nonce,
+        })
+    }
+
+    pub fn open(&self, passphrase: &SecretString) -> Result<SecretBytes, failure::Error> {
+        let key = PassphraseDerivedKey::from(passphrase, &self.salt, &self.kdf)?;
+        open(&self.encrypted_data, &self.nonce, &key)
+    }
+
+    pub fn passphrase_salt(&self) -> String {
+        self.salt.encode_base64()
+    }
+
+    pub fn kdf(&self) -> KdfParams {
+        self.kdf
+    }
+
+    pub fn data(&self) -> String {
+        base64::encode(&self.encrypted_data)
+    }
+
+    pub fn nonce(&self) -> String {
+        base64::encode(&self.nonce)
+    }
+}
+
+/// Encrypts `plaintext` with a random nonce. Returns the ciphertext and the
+/// nonce.
+pub fn seal(
+    plaintext: &[u8],
+    key: &PassphraseDerivedKey,
+) -> Result<(Vec<u8>, Vec<u8>), failure::Error> {
+    let mut nonce: [u8; SECRETBOX_NONCE_SIZE] = [0; SECRETBOX_NONCE_SIZE];
+    super::randombytes_into(&mut nonce);
+    let nonce = nonce; // No longer mutable
+
+    let mut cipher_text = vec![0; plaintext.len() + SECRETBOX_MAC_SIZE];
+
+    let result;
+    unsafe {
+        result = libsodium_sys::crypto_secretbox_easy(
+            cipher_text.as_mut_ptr(),
+            plaintext.as_ptr(),
+            plaintext.len() as u64,
+            nonce.as_ptr(),
+            key.as_ptr(),
+        );
+    }
+
+    if result != 0 {
+        bail!("Error while encrypting.");
+    }
+
+    Ok((cipher_text, nonce.to_vec()))
+}
+
+pub fn open(
+    cipher_text: &[u8],
+    nonce: &[u8],
+    key: &PassphraseDerivedKey,
+) -> Result<SecretBytes, failure::Error> {
+    if cipher_text.len() < SECRETBOX_MAC_SIZE || nonce.len() != SECRETBOX_NONCE_SIZE {
+        bail!("Invalid encrypted data.");
+    }
+
+    let mut plain_text = SecretBytes::new(cipher_text.len() - SECRETBOX_MAC_SIZE);
+
+    let result;
+    unsafe {
+        result = libsodium_sys::crypto_secretbox_open_easy(
+            plain_text.as_mut_ptr(),
+            cipher_text.as_ptr(),
+            cipher_text.len() as u64,
+            nonce.as_ptr(),
+            key.as_ptr(),
+        );
+    }
+
+    if result != 0 {
+        bail!("Unable to decrypt."); // TODO: Be more specific
+    }
+
+    Ok(plain_text)
+}
@@ -0,0 +1,232 @@
+use super::secret::SecretBytes;
+use failure::bail;
+
+// Shamir's secret sharing over GF(2^8), one byte of the secret at a time:
+// https://en.wikipedia.org/wiki/Shamir%27s_Secret_Sharing
+//
+// Each byte of the secret becomes the constant term of a random polynomial
+// of degree (threshold - 1). Share `x` holds that polynomial evaluated at
+// `x`. Any `threshold` shares pin down the polynomial (and thus the secret),
+// while fewer shares reveal nothing about it.
+
+pub struct KeyShare {
+    x: u8,
+    data: SecretBytes,
+}
+
+impl KeyShare {
+    pub fn from_bytes(bytes: &[u8]) -> Result<KeyShare, failure::Error> {
+        if bytes.len() < 2 || bytes[0] == 0 {
+            bail!("Invalid key share.");
+        }
+
+        Ok(KeyShare {
+            x: bytes[0],
+            data: SecretBytes::from_slice(&bytes[1..]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> SecretBytes {
+        let mut bytes = SecretBytes::new(self.data.len() + 1);
+        bytes.as_mut_slice()[0] = self.x;
+        bytes.as_mut_slice()[1..].copy_from_slice(self.data.as_slice());
+        bytes
+    }
+
+    /// Identifies which share this is. No two shares of a secret have the
+    /// same index.
+    pub fn index(&self) -> u8 {
+        self.x
+    }
+}
+
+/// Splits `secret` into `count` shares, any `threshold` of which can
+/// reconstruct it.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<KeyShare>, failure::Error> {
+    if threshold < 2 {
+        bail!("A secret must be split with a threshold of at least 2.");
+    }
+
+    if count < threshold {
+        bail!(
+            "Can't require {} shares when there are only {}.",
+            threshold,
+            count
+        );
+    }
+
+    let mut shares: Vec<KeyShare> = (1..=count)
+        .map(|x| KeyShare {
+            x: x,
+            data: SecretBytes::new(secret.len()),
+        })
+        .collect();
+
+    let mut coefficients = SecretBytes::new(threshold as usize);
+    for (i, byte) in secret.iter().enumerate() {
+        let coefficients = coefficients.as_mut_slice();
+        coefficients[0] = *byte;
+        super::randombytes_into(&mut coefficients[1..]);
+
+        for share in shares.iter_mut() {
+            share.data.as_mut_slice()[i] = evaluate(coefficients, share.x);
+        }
+    }
+
+    Ok(shares)
+}
+
+/// Reconstructs a secret from its shares. Passing fewer shares than the
+/// threshold the secret was split with silently produces garbage, so callers
+/// need to know the threshold.
+pub fn combine(shares: &[KeyShare]) -> Result<SecretBytes, failure::Error> {
+    if shares.is_empty() {
+        bail!("No key shares to combine.");
+    }
+
+    let len = shares[0].data.len();
+    if shares.iter().any(|s| s.data.len() != len) {
+        bail!("Key shares have different lengths.");
+    }
+
+    for (i, share) in shares.iter().enumerate() {
+        if shares[0..i].iter().any(|s| s.x == share.x) {
+            bail!("The same key share was provided twice.");
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at x = 0. In GF(2^8), subtraction
+    // is the same as addition (XOR).
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|share_i| {
+            shares
+                .iter()
+                .filter(|share_j| share_j.x != share_i.x)
+                .fold(1, |acc, share_j| {
+                    gf_mul(acc, gf_div(share_j.x, share_j.x ^ share_i.x))
+                })
+        })
+        .collect();
+
+    let mut secret = SecretBytes::new(len);
+    for (byte, out) in secret.as_mut_slice().iter_mut().enumerate() {
+        *out = shares.iter().zip(basis.iter()).fold(0, |acc, (share, b)| {
+            acc ^ gf_mul(share.data.as_slice()[byte], *b)
+        });
+    }
+
+    Ok(secret)
+}
+
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    // Horner's method
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |acc, coefficient| gf_mul(acc, x) ^ coefficient)
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial (x^8 + x^4 + x^3 + x + 1).
+/// Avoids branching on its inputs, since they are secret.
+fn gf_mul(a: u8, b: u8) -> u8 {
+    let mut a = a;
+    let mut b = b;
+    let mut product = 0;
+
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = a >> 7;
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(carry));
+        b >>= 1;
+    }
+
+    product
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^254 is the multiplicative inverse of b
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+
+    gf_mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn secret() -> Vec<u8> {
+        (0..32).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn subset(shares: &[KeyShare], indexes: &[usize]) -> Vec<KeyShare> {
+        indexes
+            .iter()
+            .map(|i| KeyShare::from_bytes(shares[*i].to_bytes().as_slice()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn field_arithmetic() {
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1); // Example from FIPS 197
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(gf_div(1, a), a), 1);
+        }
+    }
+
+    #[test]
+    fn any_two_of_three() {
+        crate::libsodium::init().unwrap();
+
+        let shares = split(&secret(), 2, 3).unwrap();
+        let pairs = [[0, 1], [0, 2], [1, 2], [2, 0]];
+
+        for pair in pairs.iter() {
+            assert_eq!(
+                combine(&subset(&shares, pair)).unwrap().as_slice(),
+                &secret()[..]
+            );
+        }
+    }
+
+    #[test]
+    fn three_of_five() {
+        crate::libsodium::init().unwrap();
+
+        let shares = split(&secret(), 3, 5).unwrap();
+
+        assert_eq!(
+            combine(&subset(&shares, &[0, 2, 4])).unwrap().as_slice(),
+            &secret()[..]
+        );
+        assert_eq!(
+            combine(&subset(&shares, &[4, 3, 1])).unwrap().as_slice(),
+            &secret()[..]
+        );
+        assert_eq!(
+            combine(&subset(&shares, &[0, 1, 2, 3, 4]))
+                .unwrap()
+                .as_slice(),
+            &secret()[..]
+        );
+        assert_ne!(
+            combine(&subset(&shares, &[0, 1])).unwrap().as_slice(),
+            &secret()[..]
+        );
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        crate::libsodium::init().unwrap();
+
+        assert!(split(&secret(), 1, 3).is_err());
+        assert!(split(&secret(), 4, 3).is_err());
+
+        let shares = split(&secret(), 2, 3).unwrap();
+        assert!(combine(&subset(&shares, &[1, 1])).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use super::hash;
+use super::secret::{SecretBytes, SecretString};
+use failure::{bail, format_err};
+use libsodium_sys;
+
+// public-key signature docs:
+// https://download.libsodium.org/doc/public-key_cryptography/public-key_signatures
+
+const PUBLIC_KEY_SIZE: usize = libsodium_sys::crypto_sign_PUBLICKEYBYTES as usize;
+const SECRET_KEY_SIZE: usize = libsodium_sys::crypto_sign_SECRETKEYBYTES as usize;
+const SIGNATURE_SIZE: usize = libsodium_sys::crypto_sign_BYTES as usize;
+
+/// The owner's Ed25519 key, used to sign the packages they compile
+pub struct SigningKey {
+    data: SecretBytes,
+}
+
+/// The half of the owner's key that backup holders use to check signatures
+#[derive(Clone, PartialEq)]
+pub struct PublicKey {
+    data: Vec<u8>,
+}
+
+impl SigningKey {
+    pub fn new() -> SigningKey {
+        let mut key = SigningKey {
+            data: SecretBytes::new(SECRET_KEY_SIZE),
+        };
+        let mut public_key: [u8; PUBLIC_KEY_SIZE] = [0; PUBLIC_KEY_SIZE];
+
+        unsafe {
+            libsodium_sys::crypto_sign_keypair(public_key.as_mut_ptr(), key.data.as_mut_ptr());
+        }
+
+        key
+    }
+
+    pub fn decode_base64(base64_contents: &SecretString) -> Result<SigningKey, failure::Error> {
+        let decoded = SecretBytes::from_vec(base64::decode(base64_contents.as_bytes())?);
+
+        if decoded.len() != SECRET_KEY_SIZE {
+            return Err(format_err!(
+                "base64 data is an invalid length (was {} bytes, should be {})",
+                decoded.len(),
+                SECRET_KEY_SIZE
+            ));
+        }
+
+        Ok(SigningKey { data: decoded })
+    }
+
+    pub fn encode_base64(&self) -> SecretString {
+        SecretString::from(base64::encode(self.data.as_slice()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut public_key = vec![0; PUBLIC_KEY_SIZE];
+
+        unsafe {
+            libsodium_sys::crypto_sign_ed25519_sk_to_pk(
+                public_key.as_mut_ptr(),
+                self.data.as_ptr(),
+            );
+        }
+
+        PublicKey { data: public_key }
+    }
+
+    /// Returns a detached signature of `message`
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let mut signature = vec![0; SIGNATURE_SIZE];
+
+        unsafe {
+            libsodium_sys::crypto_sign_detached(
+                signature.as_mut_ptr(),
+                std::ptr::null_mut(),
+                message.as_ptr(),
+                message.len() as u64,
+                self.data.as_ptr(),
+            );
+        }
+
+        signature
+    }
+}
+
+impl PublicKey {
+    pub fn decode_base64(base64_contents: &String) -> Result<PublicKey, failure::Error> {
+        let decoded = base64::decode(base64_contents)?;
+
+        if decoded.len() != PUBLIC_KEY_SIZE {
+            bail!("Invalid public key.");
+        }
+
+        Ok(PublicKey { data: decoded })
+    }
+
+    pub fn encode_base64(&self) -> String {
+        base64::encode(&self.data)
+    }
+
+    /// Fails unless `signature` was made over `message` by this key's owner
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), failure::Error> {
+        if signature.len() != SIGNATURE_SIZE {
+            bail!("Invalid signature.");
+        }
+
+        let result = unsafe {
+            libsodium_sys::crypto_sign_verify_detached(
+                signature.as_ptr(),
+                message.as_ptr(),
+                message.len() as u64,
+                self.data.as_ptr(),
+            )
+        };
+
+        if result != 0 {
+            bail!("Signature doesn't match.");
+        }
+
+        Ok(())
+    }
+
+    /// A short, human-comparable form of the key, e.g. for printing in a
+    /// readme: the first 128 bits of its SHA-256 hash, in groups of four hex
+    /// digits.
+    pub fn fingerprint(&self) -> String {
+        let hash = hash::to_hex(&hash::sha256(&self.data));
+
+        hash.as_bytes()[0..32]
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        crate::libsodium::init().unwrap();
+
+        let key = SigningKey::new();
+        let public_key = key.public_key();
+        let signature = key.sign(b"manifest");
+
+        assert!(public_key.verify(b"manifest", &signature).is_ok());
+        assert!(public_key.verify(b"manifesto", &signature).is_err());
+        assert!(SigningKey::new()
+            .public_key()
+            .verify(b"manifest", &signature)
+            .is_err());
+
+        let decoded = SigningKey::decode_base64(&key.encode_base64()).unwrap();
+        assert!(decoded.public_key() == public_key);
+        assert_eq!(public_key.fingerprint().len(), 39);
+    }
+}
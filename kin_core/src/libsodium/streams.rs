@@ -1,4 +1,6 @@
+use super::header::{ArchiveHeader, FIRST_VERSION_WITH_AD};
 use super::masterkey::{MasterKey, MASTER_KEY_SIZE};
+use super::secret::SecretBytes;
 use failure::bail;
 use std::io::{Read, Write};
 use std::ptr;
@@ -9,79 +11,319 @@ use std::ptr;
 const STREAM_HEADER_SIZE: usize =
     libsodium_sys::crypto_secretstream_xchacha20poly1305_HEADERBYTES as usize;
 const A_SIZE: usize = libsodium_sys::crypto_secretstream_xchacha20poly1305_ABYTES as usize;
-const PLAINTEXT_BUF_SIZE: usize = 16384; // 16 KiB
-const CIPHERTEXT_BUF_SIZE: usize = PLAINTEXT_BUF_SIZE + A_SIZE;
+
+/// Plaintext bytes per encrypted chunk, unless the caller asks for something
+/// else. Each archive records the chunk size it was written with.
+pub const DEFAULT_CHUNK_SIZE: u32 = 16384; // 16 KiB
+const TRUNCATED_OR_CORRUPTED: &str =
+    "private archive is truncated, corrupted, or doesn't belong to this backup package";
+
+/// The secretstream state holds a copy of the key, so wipe it when we're done
+struct StreamState(libsodium_sys::crypto_secretstream_xchacha20poly1305_state);
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState(libsodium_sys::crypto_secretstream_xchacha20poly1305_state {
+            _pad: [0; 8],
+            k: [0; MASTER_KEY_SIZE],
+            nonce: [0; 12],
+        })
+    }
+}
+
+impl Drop for StreamState {
+    fn drop(&mut self) {
+        unsafe {
+            libsodium_sys::sodium_memzero(
+                &mut self.0 as *mut _ as *mut _,
+                std::mem::size_of_val(&self.0),
+            );
+        }
+    }
+}
+
+// Every chunk is authenticated together with some associated data (AD): bytes
+// that aren't stored in the archive, but must be supplied again to decrypt
+// it. Callers use it to tie an archive to the package it was compiled for.
 
 pub fn encrypt(
     key: &MasterKey,
+    associated_data: &[u8],
+    input: &mut Read,
+    output: &mut Write,
+) -> Result<(), failure::Error> {
+    encrypt_with_chunk_size(key, associated_data, DEFAULT_CHUNK_SIZE, input, output)
+}
+
+pub fn encrypt_with_chunk_size(
+    key: &MasterKey,
+    associated_data: &[u8],
+    chunk_size: u32,
     input: &mut Read,
     output: &mut Write,
 ) -> Result<(), failure::Error> {
-    let mut state = init_encrypt(&key, output)?;
-    let mut plaintext: [u8; PLAINTEXT_BUF_SIZE] = [0; PLAINTEXT_BUF_SIZE];
+    let mut encryptor = Encryptor::with_chunk_size(key, associated_data, chunk_size, output)?;
+    let mut plaintext = SecretBytes::new(chunk_size as usize);
 
     loop {
-        let read_count = read_chunk(&mut plaintext, input)?;
-        let is_final = read_count < PLAINTEXT_BUF_SIZE;
+        let read_count = read_chunk(plaintext.as_mut_slice(), input)?;
+        encryptor.write_all(&plaintext.as_slice()[0..read_count])?;
 
-        if is_final {
-            let encrypted = encrypt_chunk(&mut state, &plaintext[0..read_count], is_final)?;
-            output.write(&encrypted)?;
-            output.flush()?;
+        if read_count < plaintext.len() {
             break;
-        } else {
-            let encrypted = encrypt_chunk(&mut state, &plaintext, is_final)?;
-            output.write(&encrypted)?;
         }
     }
 
+    encryptor.finish()?;
+
     Ok(())
 }
 
+/// Encrypts everything written to it, so plaintext can be streamed straight
+/// into an encrypted archive without ever touching the disk.
+///
+/// Call `finish` after the last write. An encryptor that is dropped without
+/// being finished leaves a truncated archive behind, which won't decrypt.
+pub struct Encryptor<W: Write> {
+    output: W,
+    state: StreamState,
+    plaintext: SecretBytes,
+    plaintext_len: usize,
+    ciphertext: Vec<u8>,
+    associated_data: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(
+        key: &MasterKey,
+        associated_data: &[u8],
+        output: W,
+    ) -> Result<Encryptor<W>, failure::Error> {
+        Encryptor::with_chunk_size(key, associated_data, DEFAULT_CHUNK_SIZE, output)
+    }
+
+    /// Larger chunks mean fewer (but larger) reads and writes, at the cost
+    /// of memory on both the encrypting and decrypting side.
+    pub fn with_chunk_size(
+        key: &MasterKey,
+        associated_data: &[u8],
+        chunk_size: u32,
+        mut output: W,
+    ) -> Result<Encryptor<W>, failure::Error> {
+        let header = ArchiveHeader::new(chunk_size);
+        header.validate()?;
+        header.write(&mut output)?;
+        let state = init_encrypt(key, &mut output)?;
+
+        Ok(Encryptor {
+            output: output,
+            state: state,
+            plaintext: SecretBytes::new(chunk_size as usize),
+            plaintext_len: 0,
+            ciphertext: vec![0; chunk_size as usize + A_SIZE],
+            associated_data: associated_data.to_vec(),
+        })
+    }
+
+    /// Encrypts whatever is left as the final chunk and returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> Result<W, failure::Error> {
+        let plaintext = &self.plaintext.as_slice()[0..self.plaintext_len];
+        let len = encrypt_chunk(
+            &mut self.state,
+            plaintext,
+            &self.associated_data,
+            true,
+            &mut self.ciphertext,
+        )?;
+        self.output.write_all(&self.ciphertext[0..len])?;
+        self.output.flush()?;
+
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let chunk_size = self.plaintext.len();
+        let count = buf.len().min(chunk_size - self.plaintext_len);
+        let end = self.plaintext_len + count;
+        self.plaintext.as_mut_slice()[self.plaintext_len..end].copy_from_slice(&buf[0..count]);
+        self.plaintext_len = end;
+
+        // A full chunk is never the final one; `finish` always adds one more,
+        // even if it's empty.
+        if self.plaintext_len == chunk_size {
+            let plaintext = self.plaintext.as_slice();
+            let ad = &self.associated_data;
+            let len = encrypt_chunk(&mut self.state, plaintext, ad, false, &mut self.ciphertext)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            self.output.write_all(&self.ciphertext[0..len])?;
+            self.plaintext_len = 0;
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // Buffered plaintext can't be written out until its chunk is full
+        self.output.flush()
+    }
+}
+
 pub fn decrypt(
     key: &MasterKey,
+    associated_data: &[u8],
     input: &mut Read,
     output: &mut Write,
 ) -> Result<(), failure::Error> {
-    let mut state = init_decrypt(&key, input)?;
-    let mut ciphertext: [u8; CIPHERTEXT_BUF_SIZE] = [0; CIPHERTEXT_BUF_SIZE];
+    let mut decryptor = Decryptor::new(key, associated_data, input)?;
+    let mut plaintext = SecretBytes::new(DEFAULT_CHUNK_SIZE as usize);
 
     loop {
-        let read_count = read_chunk(&mut ciphertext, input)?;
+        let read_count = decryptor.read(plaintext.as_mut_slice())?;
+        if read_count == 0 {
+            break;
+        }
+
+        output.write_all(&plaintext.as_slice()[0..read_count])?;
+    }
+
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Decrypts an archive as it's read, so its contents can be processed without
+/// a plaintext copy ever touching the disk.
+///
+/// Every chunk is authenticated before any of it is returned, but a
+/// truncated archive can only be detected at the end. Read until `read`
+/// returns 0, and then call `check`, before trusting that you've seen
+/// everything.
+pub struct Decryptor<R: Read> {
+    input: R,
+    state: StreamState,
+    associated_data: Vec<u8>,
+    ciphertext: Vec<u8>,
+    plaintext: SecretBytes,
+    plaintext_pos: usize,
+    plaintext_len: usize,
+    finished: bool,
+
+    /// Why decryption stopped, if the archive turned out to be damaged
+    error: Option<String>,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn new(
+        key: &MasterKey,
+        associated_data: &[u8],
+        mut input: R,
+    ) -> Result<Decryptor<R>, failure::Error> {
+        let header = ArchiveHeader::read(&mut input)?;
+
+        if header.version < FIRST_VERSION_WITH_AD && !associated_data.is_empty() {
+            // Older archives aren't bound to anything. Accepting one here would
+            // let an old private.kin be passed off as part of a newer package.
+            bail!(TRUNCATED_OR_CORRUPTED);
+        }
+
+        let state = init_decrypt(key, &mut input)?;
+
+        Ok(Decryptor {
+            input: input,
+            state: state,
+            associated_data: associated_data.to_vec(),
+            ciphertext: vec![0; header.chunk_size as usize + A_SIZE],
+            plaintext: SecretBytes::new(header.chunk_size as usize),
+            plaintext_pos: 0,
+            plaintext_len: 0,
+            finished: false,
+            error: None,
+        })
+    }
+
+    /// Fails if the archive turned out to be damaged. After the first error,
+    /// `read` returns 0 as if the archive had ended, so readers that don't
+    /// expect errors (like a zip entry skipping to its end when it's dropped)
+    /// stop instead of failing again. This is the only way to tell the two
+    /// apart.
+    pub fn check(&self) -> Result<(), failure::Error> {
+        match &self.error {
+            Some(error) => bail!("{}", error),
+            None => Ok(()),
+        }
+    }
+
+    fn decrypt_next_chunk(&mut self) -> Result<(), failure::Error> {
+        let read_count = read_chunk(&mut self.ciphertext, &mut self.input)?;
 
         if read_count == 0 {
-            break; // No more data to decrypt
+            // We ran out of data before seeing the chunk tagged as final. The
+            // archive was most likely cut off on a chunk boundary.
+            bail!(TRUNCATED_OR_CORRUPTED);
         }
 
-        let is_final = read_count < CIPHERTEXT_BUF_SIZE;
-        if is_final {
-            let plaintext = decrypt_chunk(&mut state, &ciphertext[0..read_count])?;
-            output.write(&plaintext)?;
-            output.flush()?;
-            break;
-        } else {
-            let plaintext = decrypt_chunk(&mut state, &ciphertext)?;
-            output.write(&plaintext)?;
+        let (plaintext_len, tag) = decrypt_chunk(
+            &mut self.state,
+            &self.ciphertext[0..read_count],
+            &self.associated_data,
+            self.plaintext.as_mut_slice(),
+        )?;
+        self.plaintext_pos = 0;
+        self.plaintext_len = plaintext_len;
+
+        if tag == final_tag() {
+            let mut extra: [u8; 1] = [0; 1];
+            if read_chunk(&mut extra, &mut self.input)? != 0 {
+                // Nothing should ever follow the final chunk
+                bail!(TRUNCATED_OR_CORRUPTED);
+            }
+
+            self.finished = true;
+        } else if read_count < self.ciphertext.len() {
+            // A short chunk is only valid at the end of the stream, and it
+            // wasn't tagged as the final one.
+            bail!(TRUNCATED_OR_CORRUPTED);
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.plaintext_pos == self.plaintext_len {
+            if self.finished || self.error.is_some() {
+                return Ok(0);
+            }
+
+            if let Err(e) = self.decrypt_next_chunk() {
+                self.error = Some(e.to_string());
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    e.to_string(),
+                ));
+            }
+        }
+
+        let count = buf.len().min(self.plaintext_len - self.plaintext_pos);
+        let end = self.plaintext_pos + count;
+        buf[0..count].copy_from_slice(&self.plaintext.as_slice()[self.plaintext_pos..end]);
+        self.plaintext_pos = end;
+
+        Ok(count)
+    }
 }
 
-fn init_encrypt(
-    key: &MasterKey,
-    output: &mut Write,
-) -> Result<libsodium_sys::crypto_secretstream_xchacha20poly1305_state, failure::Error> {
+fn init_encrypt(key: &MasterKey, output: &mut Write) -> Result<StreamState, failure::Error> {
     let mut header: [u8; STREAM_HEADER_SIZE] = [0; STREAM_HEADER_SIZE];
-    let mut state = libsodium_sys::crypto_secretstream_xchacha20poly1305_state {
-        _pad: [0; 8],
-        k: [0; MASTER_KEY_SIZE],
-        nonce: [0; 12],
-    };
+    let mut state = StreamState::new();
 
     unsafe {
         let result = libsodium_sys::crypto_secretstream_xchacha20poly1305_init_push(
-            &mut state,
+            &mut state.0,
             header.as_mut_ptr(),
             key.as_ptr(),
         );
@@ -91,53 +333,57 @@ fn init_encrypt(
         }
     }
 
-    output.write(&header)?;
+    output.write_all(&header)?;
 
     Ok(state)
 }
 
+/// Fills `buffer` unless the reader runs out of data first. Returns how many
+/// bytes were read, so anything short of a full buffer means the end of the
+/// stream.
 fn read_chunk(buffer: &mut [u8], reader: &mut Read) -> Result<usize, std::io::Error> {
-    let iterator = reader.bytes();
     let mut byte_count = 0;
-    for byte in iterator {
-        match byte {
-            Ok(b) => buffer[byte_count] = b,
-            Err(e) => return Err(e),
-        }
 
-        byte_count = byte_count + 1;
-        if byte_count >= buffer.len() {
-            break;
+    while byte_count < buffer.len() {
+        match reader.read(&mut buffer[byte_count..]) {
+            Ok(0) => break,
+            Ok(count) => byte_count += count,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
 
     Ok(byte_count)
 }
 
+/// Encrypts `buf` into the start of `ciphertext`, which must have room for
+/// `A_SIZE` more bytes than `buf`. Returns the ciphertext length.
 fn encrypt_chunk(
-    state: &mut libsodium_sys::crypto_secretstream_xchacha20poly1305_state,
+    state: &mut StreamState,
     buf: &[u8],
+    associated_data: &[u8],
     is_final: bool,
-) -> Result<Vec<u8>, failure::Error> {
+    ciphertext: &mut [u8],
+) -> Result<usize, failure::Error> {
     let ciphertext_len = buf.len() + A_SIZE;
-    let mut ciphertext = vec![0; ciphertext_len];
+    assert!(ciphertext.len() >= ciphertext_len);
 
     unsafe {
         let tag: u8;
         if is_final {
-            tag = libsodium_sys::crypto_secretstream_xchacha20poly1305_tag_final();
+            tag = final_tag();
         } else {
             tag = 0;
         }
 
         let result = libsodium_sys::crypto_secretstream_xchacha20poly1305_push(
-            state,
+            &mut state.0,
             ciphertext.as_mut_ptr(),
             ptr::null_mut(),
             buf.as_ptr(),
             buf.len() as u64,
-            ptr::null(),
-            0,
+            associated_data.as_ptr(),
+            associated_data.len() as u64,
             tag,
         );
 
@@ -146,25 +392,20 @@ fn encrypt_chunk(
         }
     }
 
-    Ok(ciphertext)
+    Ok(ciphertext_len)
 }
 
-fn init_decrypt(
-    key: &MasterKey,
-    reader: &mut Read,
-) -> Result<libsodium_sys::crypto_secretstream_xchacha20poly1305_state, failure::Error> {
-    let mut state = libsodium_sys::crypto_secretstream_xchacha20poly1305_state {
-        _pad: [0; 8],
-        k: [0; MASTER_KEY_SIZE],
-        nonce: [0; 12],
-    };
+fn init_decrypt(key: &MasterKey, reader: &mut Read) -> Result<StreamState, failure::Error> {
+    let mut state = StreamState::new();
 
     let mut header: [u8; STREAM_HEADER_SIZE] = [0; STREAM_HEADER_SIZE];
-    reader.read_exact(&mut header)?;
+    if reader.read_exact(&mut header).is_err() {
+        bail!(TRUNCATED_OR_CORRUPTED);
+    }
 
     unsafe {
         let result = libsodium_sys::crypto_secretstream_xchacha20poly1305_init_pull(
-            &mut state,
+            &mut state.0,
             header.as_mut_ptr(),
             key.as_ptr(),
         );
@@ -177,35 +418,345 @@ fn init_decrypt(
     Ok(state)
 }
 
+/// Decrypts `buf` into the start of `plaintext`. Returns the plaintext
+/// length and the chunk's tag.
 fn decrypt_chunk(
-    state: &mut libsodium_sys::crypto_secretstream_xchacha20poly1305_state,
+    state: &mut StreamState,
     buf: &[u8],
-) -> Result<Vec<u8>, failure::Error> {
-    if buf.len() < A_SIZE {
-        bail!("buffer size must be at least {} bytes", A_SIZE);
+    associated_data: &[u8],
+    plaintext: &mut [u8],
+) -> Result<(usize, u8), failure::Error> {
+    if buf.len() < A_SIZE || buf.len() - A_SIZE > plaintext.len() {
+        bail!(TRUNCATED_OR_CORRUPTED);
     }
 
     let plaintext_len = buf.len() - A_SIZE;
-    let mut plaintext = vec![0; plaintext_len];
     let mut tag: u8 = 0;
 
     let result;
     unsafe {
         result = libsodium_sys::crypto_secretstream_xchacha20poly1305_pull(
-            state,
+            &mut state.0,
             plaintext.as_mut_ptr(),
             ptr::null_mut(),
             &mut tag,
             buf.as_ptr(),
             buf.len() as u64,
-            ptr::null(),
-            0,
+            associated_data.as_ptr(),
+            associated_data.len() as u64,
         );
     }
 
     if result != 0 {
-        bail!("libsodium decryption failed");
+        bail!(TRUNCATED_OR_CORRUPTED);
+    }
+
+    Ok((plaintext_len, tag))
+}
+
+fn final_tag() -> u8 {
+    unsafe { libsodium_sys::crypto_secretstream_xchacha20poly1305_tag_final() }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::header::HEADER_SIZE;
+    use super::*;
+
+    const CHUNK: usize = DEFAULT_CHUNK_SIZE as usize;
+    const CIPHERTEXT_CHUNK: usize = CHUNK + A_SIZE;
+    const PREAMBLE: usize = HEADER_SIZE + STREAM_HEADER_SIZE;
+    const AD: &[u8] = b"package 1";
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn encrypt_bytes(key: &MasterKey, plaintext: &[u8]) -> Vec<u8> {
+        let mut input = plaintext;
+        let mut output = Vec::new();
+        encrypt(key, AD, &mut input, &mut output).unwrap();
+        output
+    }
+
+    fn decrypt_bytes(key: &MasterKey, ciphertext: &[u8]) -> Result<Vec<u8>, failure::Error> {
+        let mut input = ciphertext;
+        let mut output = Vec::new();
+        decrypt(key, AD, &mut input, &mut output)?;
+        Ok(output)
+    }
+
+    fn assert_truncated_or_corrupted(result: Result<Vec<u8>, failure::Error>) {
+        match result {
+            Ok(_) => panic!("decryption should have failed"),
+            Err(e) => assert_eq!(e.to_string(), TRUNCATED_OR_CORRUPTED),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let sizes = [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, CHUNK * 2, CHUNK * 3 + 5];
+
+        for size in sizes.iter() {
+            let original = plaintext(*size);
+            let ciphertext = encrypt_bytes(&key, &original);
+            let decrypted = decrypt_bytes(&key, &ciphertext).unwrap();
+            assert_eq!(decrypted, original, "round trip failed for {} bytes", size);
+        }
+    }
+
+    #[test]
+    fn encryptor_accepts_writes_of_any_size() {
+        crate::libsodium::init().unwrap();
+
+        let key = MasterKey::new();
+        let plaintext = plaintext(CHUNK * 2 + 100);
+
+        let mut encryptor = Encryptor::new(&key, AD, Vec::new()).unwrap();
+        for piece in plaintext.chunks(CHUNK / 3 + 7) {
+            encryptor.write_all(piece).unwrap();
+        }
+        let ciphertext = encryptor.finish().unwrap();
+
+        assert_eq!(ciphertext.len(), encrypt_bytes(&key, &plaintext).len());
+        assert_eq!(decrypt_bytes(&key, &ciphertext).unwrap(), plaintext);
     }
 
-    Ok(plaintext)
+    #[test]
+    fn chunk_size_is_recorded_in_header() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let original = plaintext(10000);
+        let mut input = &original[..];
+        let mut ciphertext = Vec::new();
+        encrypt_with_chunk_size(&key, AD, 4096, &mut input, &mut ciphertext).unwrap();
+
+        let header = ArchiveHeader::read(&mut &ciphertext[..]).unwrap();
+        assert_eq!(header.chunk_size, 4096);
+        assert_eq!(
+            ciphertext.len(),
+            PREAMBLE + (4096 + A_SIZE) * 2 + 1808 + A_SIZE
+        );
+        assert_eq!(decrypt_bytes(&key, &ciphertext).unwrap(), original);
+
+        assert!(Encryptor::with_chunk_size(&key, AD, 16, Vec::new()).is_err());
+    }
+
+    /// Hands out at most a few bytes per call, and is sometimes interrupted
+    struct StingyReader<'a> {
+        data: &'a [u8],
+        calls: usize,
+    }
+
+    impl<'a> Read for StingyReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 5 == 0 {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+
+            let count = buf.len().min(self.data.len()).min(self.calls % 7 + 1);
+            buf[0..count].copy_from_slice(&self.data[0..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn short_reads_fill_whole_chunks() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let original = plaintext(CHUNK * 2 + 3);
+        let mut input = StingyReader {
+            data: &original,
+            calls: 0,
+        };
+        let mut ciphertext = Vec::new();
+        encrypt(&key, AD, &mut input, &mut ciphertext).unwrap();
+
+        // Same layout as if everything had arrived in one read
+        assert_eq!(
+            ciphertext.len(),
+            PREAMBLE + CIPHERTEXT_CHUNK * 2 + 3 + A_SIZE
+        );
+        assert_eq!(decrypt_bytes(&key, &ciphertext).unwrap(), original);
+    }
+
+    #[test]
+    fn exact_multiple_of_chunk_size_ends_with_empty_final_chunk() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2));
+        assert_eq!(ciphertext.len(), PREAMBLE + CIPHERTEXT_CHUNK * 2 + A_SIZE);
+
+        // Dropping the empty final chunk leaves a stream that ends cleanly on
+        // a chunk boundary, which must still be detected.
+        let truncated = &ciphertext[0..PREAMBLE + CIPHERTEXT_CHUNK * 2];
+        assert_truncated_or_corrupted(decrypt_bytes(&key, truncated));
+    }
+
+    #[test]
+    fn truncated_on_chunk_boundary() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2 + 100));
+
+        for chunk_count in 0..3 {
+            let truncated = &ciphertext[0..PREAMBLE + CIPHERTEXT_CHUNK * chunk_count];
+            assert_truncated_or_corrupted(decrypt_bytes(&key, truncated));
+        }
+    }
+
+    #[test]
+    fn truncated_mid_chunk() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2 + 100));
+
+        let cut_points = [
+            PREAMBLE - 1,
+            PREAMBLE + 1,
+            PREAMBLE + A_SIZE,
+            PREAMBLE + CIPHERTEXT_CHUNK + 1,
+            ciphertext.len() - 1,
+        ];
+
+        for cut in cut_points.iter() {
+            assert_truncated_or_corrupted(decrypt_bytes(&key, &ciphertext[0..*cut]));
+        }
+    }
+
+    #[test]
+    fn bit_flips() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2 + 100));
+
+        let positions = [
+            HEADER_SIZE,                     // secretstream header
+            PREAMBLE,                        // first chunk
+            PREAMBLE + CIPHERTEXT_CHUNK - 1, // first chunk's MAC
+            PREAMBLE + CIPHERTEXT_CHUNK * 2, // final chunk
+            ciphertext.len() - 1,            // final chunk's MAC
+        ];
+
+        for position in positions.iter() {
+            let mut tampered = ciphertext.clone();
+            tampered[*position] ^= 0x01;
+            assert_truncated_or_corrupted(decrypt_bytes(&key, &tampered));
+        }
+    }
+
+    #[test]
+    fn decryptor_stops_after_an_error() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2 + 100));
+        let truncated = &ciphertext[0..PREAMBLE + CIPHERTEXT_CHUNK + 1];
+
+        let mut decryptor = Decryptor::new(&key, AD, truncated).unwrap();
+        let mut output = Vec::new();
+        assert!(decryptor.read_to_end(&mut output).is_err());
+        assert_eq!(output.len(), CHUNK);
+        assert!(decryptor.check().is_err());
+
+        // Reads as if the archive had ended, but is still damaged
+        assert_eq!(decryptor.read(&mut [0; 10]).unwrap(), 0);
+        assert_eq!(
+            decryptor.check().unwrap_err().to_string(),
+            TRUNCATED_OR_CORRUPTED
+        );
+
+        let mut decryptor = Decryptor::new(&key, AD, &ciphertext[..]).unwrap();
+        decryptor.read_to_end(&mut output).unwrap();
+        assert!(decryptor.check().is_ok());
+    }
+
+    #[test]
+    fn trailing_data() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let mut ciphertext = encrypt_bytes(&key, &plaintext(100));
+        ciphertext.push(0);
+        assert_truncated_or_corrupted(decrypt_bytes(&key, &ciphertext));
+    }
+
+    #[test]
+    fn swapped_chunks() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let ciphertext = encrypt_bytes(&key, &plaintext(CHUNK * 2 + 100));
+
+        let first = PREAMBLE..PREAMBLE + CIPHERTEXT_CHUNK;
+        let second = PREAMBLE + CIPHERTEXT_CHUNK..PREAMBLE + CIPHERTEXT_CHUNK * 2;
+
+        let mut tampered = ciphertext[0..PREAMBLE].to_vec();
+        tampered.extend_from_slice(&ciphertext[second]);
+        tampered.extend_from_slice(&ciphertext[first]);
+        tampered.extend_from_slice(&ciphertext[PREAMBLE + CIPHERTEXT_CHUNK * 2..]);
+
+        assert_truncated_or_corrupted(decrypt_bytes(&key, &tampered));
+    }
+
+    #[test]
+    fn wrong_key() {
+        crate::libsodium::init().unwrap();
+
+        let ciphertext = encrypt_bytes(&MasterKey::new(), &plaintext(100));
+        assert_truncated_or_corrupted(decrypt_bytes(&MasterKey::new(), &ciphertext));
+    }
+
+    #[test]
+    fn wrong_associated_data() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let original = plaintext(CHUNK + 100);
+        let ciphertext = encrypt_bytes(&key, &original);
+
+        let mut output = Vec::new();
+        let result = decrypt(&key, b"package 2", &mut &ciphertext[..], &mut output);
+        assert_truncated_or_corrupted(result.map(|_| output));
+    }
+
+    #[test]
+    fn unbound_archives_need_empty_associated_data() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        // Same as an archive written before associated data was added
+        let original = plaintext(100);
+        let mut ciphertext = Vec::new();
+        encrypt(&key, &[], &mut &original[..], &mut ciphertext).unwrap();
+        ciphertext[8..10].copy_from_slice(&1u16.to_le_bytes());
+
+        let mut output = Vec::new();
+        decrypt(&key, &[], &mut &ciphertext[..], &mut output).unwrap();
+        assert_eq!(output, original);
+
+        assert_truncated_or_corrupted(decrypt_bytes(&key, &ciphertext));
+    }
+
+    #[test]
+    fn not_an_archive() {
+        crate::libsodium::init().unwrap();
+        let key = MasterKey::new();
+
+        let mut ciphertext = encrypt_bytes(&key, &plaintext(100));
+        ciphertext[0] ^= 0x01;
+        assert!(decrypt_bytes(&key, &ciphertext).is_err());
+    }
 }
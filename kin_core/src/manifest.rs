@@ -0,0 +1,238 @@
+use super::backuppackage::BackupPackage;
+use super::fsutil;
+use super::libsodium;
+use super::libsodium::{PublicKey, SigningKey};
+use super::Error;
+use failure::bail;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Lists a hash of every file in a compiled package. The owner signs it, so
+/// backup holders can tell whether anything in it changed after it was
+/// signed. The signature alone doesn't show who made the package; private.kin
+/// is bound to the owner's key through the archive's associated data.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub owner: String,
+    pub owner_public_key: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Relative to the package directory, with `/` as the separator
+    pub path: String,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Hashes everything currently in the package
+    pub fn build(
+        package: &BackupPackage,
+        owner: &String,
+        public_key: &PublicKey,
+    ) -> Result<Manifest, Error> {
+        Ok(Manifest {
+            owner: owner.clone(),
+            owner_public_key: public_key.encode_base64(),
+            files: hash_files(package)?,
+        })
+    }
+
+    /// Writes the manifest into the package along with its signature
+    pub fn write(&self, package: &BackupPackage, key: &SigningKey) -> Result<(), Error> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        let signature = base64::encode(&key.sign(serialized.as_bytes()));
+
+        for (path, contents) in [
+            (package.manifest_path(), serialized),
+            (package.manifest_signature_path(), signature),
+        ]
+        .iter()
+        {
+            let mut file = File::create(path)?;
+            file.write_all(contents.as_bytes())?;
+            fsutil::set_readonly(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the package's manifest, checking that it was signed by the key
+    /// it names, and that private.kin is bound to the same key. That only
+    /// shows the package is consistent: anyone can make a new key and
+    /// re-sign everything. Only the owner's private.kin decrypts with the
+    /// owner's key, though.
+    pub fn read_verified(package: &BackupPackage) -> Result<Manifest, Error> {
+        let serialized = fs::read(package.manifest_path())?;
+        let signature = base64::decode(&fs::read(package.manifest_signature_path())?)?;
+
+        let manifest: Manifest = serde_json::from_slice(&serialized)?;
+        let public_key = manifest.public_key()?;
+        if public_key.verify(&serialized, &signature).is_err() {
+            bail!("the package manifest's signature is invalid; the package may have been tampered with");
+        }
+
+        match package.owner_public_key()? {
+            Some(ref bound) if bound != &public_key => bail!(
+                "the package manifest is signed with a different key than the one private.kin belongs to; the package may have been tampered with"
+            ),
+            _ => {}
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey, Error> {
+        PublicKey::decode_base64(&self.owner_public_key)
+    }
+
+    /// Returns the listed files that are missing from the package, or whose
+    /// contents don't match their hash.
+    pub fn changed_files(&self, package: &BackupPackage) -> Result<Vec<String>, Error> {
+        let mut changed = Vec::new();
+
+        for entry in self.files.iter() {
+            let path = package.path().join(&entry.path);
+            let matches = match File::open(&path) {
+                Ok(mut file) => {
+                    libsodium::to_hex(&libsodium::sha256_reader(&mut file)?) == entry.sha256
+                }
+                Err(_) => false,
+            };
+
+            if !matches {
+                changed.push(entry.path.clone());
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Returns files in the package that the manifest doesn't list. Files
+    /// outside Kin's own directories are left out, since the drive a package
+    /// is on may hold anything else.
+    pub fn unlisted_files(&self, package: &BackupPackage) -> Result<Vec<String>, Error> {
+        let unlisted = package_files(package)?
+            .iter()
+            .map(|path| relative_path(&package.path(), path))
+            .filter(|path| !self.files.iter().any(|entry| &entry.path == path))
+            .collect();
+
+        Ok(unlisted)
+    }
+}
+
+fn hash_files(package: &BackupPackage) -> Result<Vec<ManifestEntry>, Error> {
+    let mut entries = Vec::new();
+    for path in package_files(package)? {
+        let mut file = File::open(&path)?;
+        entries.push(ManifestEntry {
+            path: relative_path(&package.path(), &path),
+            sha256: libsodium::to_hex(&libsodium::sha256_reader(&mut file)?),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Everything Kin puts in a package, except the manifest itself
+fn package_files(package: &BackupPackage) -> Result<Vec<PathBuf>, Error> {
+    let exclude = [package.manifest_path(), package.manifest_signature_path()];
+
+    let mut paths = Vec::new();
+    for dir in [package.config_dir_path(), package.decrypt_exe_dir()].iter() {
+        if dir.is_dir() {
+            list_files(dir, &mut paths)?;
+        }
+    }
+    for path in [
+        package.public_archive_path(),
+        package.overview_readme_path(),
+    ]
+    .iter()
+    {
+        if path.exists() {
+            paths.push(path.clone());
+        }
+    }
+    paths.retain(|path| !exclude.contains(path));
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn list_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        if item.file_type()?.is_dir() {
+            list_files(&item.path(), paths)?;
+        } else {
+            paths.push(item.path());
+        }
+    }
+
+    Ok(())
+}
+
+fn relative_path(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap()
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::libsodium::SigningKey;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn only_kin_files_are_listed() {
+        let temp = TempDir::new();
+        let package = BackupPackage::from(temp.path());
+        fs::create_dir_all(package.config_dir_path()).unwrap();
+        fs::create_dir_all(package.decrypt_exe_dir()).unwrap();
+        for path in [
+            package.config_file_path(),
+            package.public_archive_path(),
+            package.overview_readme_path(),
+            package.decrypt_readme_path(),
+        ]
+        .iter()
+        {
+            fs::write(path, "").unwrap();
+        }
+
+        let key = SigningKey::new().public_key();
+        let manifest = Manifest::build(&package, &String::from("Owen"), &key).unwrap();
+        let listed: Vec<&str> = manifest.files.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            listed,
+            vec![
+                ".kin/config.json",
+                "private/readme.html",
+                "public.zip",
+                "readme.html"
+            ]
+        );
+
+        fs::write(temp.path().join("photos.zip"), "").unwrap();
+        fs::create_dir(temp.path().join("music")).unwrap();
+        fs::write(temp.path().join("music/song.mp3"), "").unwrap();
+        assert!(manifest.unlisted_files(&package).unwrap().is_empty());
+
+        fs::write(package.decrypt_exe_dir().join("decrypt.exe"), "").unwrap();
+        assert_eq!(
+            manifest.unlisted_files(&package).unwrap(),
+            vec!["private/decrypt.exe"]
+        );
+    }
+}
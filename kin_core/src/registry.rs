@@ -0,0 +1,195 @@
+use super::backuppackage::BackupPackage;
+use super::kinsettings::first_key_generation;
+use super::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Every package compiled from a project, so the owner can tell which ones
+/// are still out there and which need to be reissued
+#[derive(Serialize, Deserialize, Default)]
+pub struct PackageRegistry {
+    packages: Vec<IssuedPackage>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IssuedPackage {
+    pub package_id: String,
+    pub recipient: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+
+    /// The recipients whose passphrases have key slots in this package
+    pub peers: Vec<String>,
+
+    /// Which of the project's master keys the package was compiled with
+    #[serde(default = "first_key_generation")]
+    pub key_generation: u32,
+
+    /// `KinProject::content_hash` when the package was compiled. Empty for
+    /// packages compiled before it was recorded.
+    #[serde(default)]
+    pub content_hash: String,
+
+    /// Why this package needs to be reissued, if it does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale: Option<String>,
+}
+
+impl PackageRegistry {
+    /// Reads the registry. Projects that haven't compiled anything since the
+    /// registry was introduced don't have one yet.
+    pub fn read(path: &PathBuf) -> Result<PackageRegistry, Error> {
+        if !path.exists() {
+            return Ok(PackageRegistry::default());
+        }
+
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<(), Error> {
+        let serialized = serde_json::to_string_pretty(self)?;
+
+        let file = File::create(path)?;
+        let mut file = BufWriter::new(file);
+        file.write_all(serialized.as_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, package: IssuedPackage) {
+        self.packages.push(package);
+    }
+
+    pub fn get(&self, package_id: &str) -> Option<&IssuedPackage> {
+        self.packages.iter().find(|x| x.package_id == package_id)
+    }
+
+    /// Who `package` was compiled for. The registry follows recipients when
+    /// they're renamed; the package itself doesn't, so it's only asked if
+    /// the registry has no record of it.
+    pub fn compiled_for(&self, package: &BackupPackage) -> Result<Option<String>, Error> {
+        match self.get(&package.package_id()?) {
+            Some(issued) => Ok(Some(issued.recipient.clone())),
+            None => package.recipient(),
+        }
+    }
+
+    /// The most recent package compiled for each recipient. Older ones are
+    /// assumed to have been replaced.
+    pub fn latest(&self) -> Vec<&IssuedPackage> {
+        self.packages
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| {
+                !self.packages[i + 1..]
+                    .iter()
+                    .any(|newer| newer.recipient == x.recipient)
+            })
+            .map(|(_, x)| x)
+            .collect()
+    }
+
+    pub fn latest_for(&self, recipient: &String) -> Option<&IssuedPackage> {
+        self.packages
+            .iter()
+            .rev()
+            .find(|x| &x.recipient == recipient)
+    }
+
+    /// Marks each recipient's latest package as needing to be reissued if
+    /// `reason` returns a reason for it. Returns the packages that were
+    /// marked.
+    pub fn mark_stale<F>(&mut self, reason: F) -> Vec<IssuedPackage>
+    where
+        F: Fn(&IssuedPackage) -> Option<String>,
+    {
+        let latest: Vec<String> = self.latest().iter().map(|x| x.package_id.clone()).collect();
+
+        let mut marked = Vec::new();
+        for package in self.packages.iter_mut() {
+            if !latest.contains(&package.package_id) {
+                continue;
+            }
+
+            if let Some(reason) = reason(package) {
+                package.stale = Some(reason);
+                marked.push(package.clone());
+            }
+        }
+
+        marked
+    }
+
+    pub fn rename_recipient(&mut self, name: &String, new_name: &String) {
+        for package in self.packages.iter_mut() {
+            if &package.recipient == name {
+                package.recipient = new_name.clone();
+            }
+
+            for peer in package.peers.iter_mut().filter(|x| x == &name) {
+                *peer = new_name.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::issued_package as package;
+
+    #[test]
+    fn only_latest_packages_are_marked_stale() {
+        let mut registry = PackageRegistry::default();
+        registry.record(package("1", "Alice", &["Bob", "Chuck"]));
+        registry.record(package("2", "Bob", &["Alice", "Chuck"]));
+        registry.record(package("3", "Chuck", &["Alice", "Bob"]));
+        registry.record(package("4", "Alice", &["Bob", "Chuck"]));
+
+        let latest: Vec<&str> = registry
+            .latest()
+            .iter()
+            .map(|x| x.package_id.as_str())
+            .collect();
+        assert_eq!(latest, vec!["2", "3", "4"]);
+        assert_eq!(
+            registry
+                .latest_for(&String::from("Alice"))
+                .unwrap()
+                .package_id,
+            "4"
+        );
+        assert!(registry.latest_for(&String::from("Dana")).is_none());
+
+        let marked = registry.mark_stale(|x| {
+            if x.peers.contains(&String::from("Bob")) {
+                Some(String::from("has Bob's old passphrase"))
+            } else {
+                None
+            }
+        });
+        let marked: Vec<&str> = marked.iter().map(|x| x.package_id.as_str()).collect();
+        assert_eq!(marked, vec!["3", "4"]);
+
+        assert!(registry.get("1").unwrap().stale.is_none());
+        assert!(registry.get("2").unwrap().stale.is_none());
+        assert!(registry.get("4").unwrap().stale.is_some());
+    }
+
+    #[test]
+    fn rename_recipient() {
+        let mut registry = PackageRegistry::default();
+        registry.record(package("1", "Alice", &["Bob"]));
+        registry.record(package("2", "Bob", &["Alice"]));
+
+        registry.rename_recipient(&String::from("Bob"), &String::from("Robert"));
+
+        assert_eq!(registry.get("1").unwrap().peers, vec!["Robert"]);
+        assert_eq!(registry.get("2").unwrap().recipient, "Robert");
+    }
+}
@@ -0,0 +1,76 @@
+//! Helpers shared by the tests in kin_core and kin. kin's tests get them
+//! through the "test-support" feature.
+
+use super::kinsettings::{KinRecipient, KinSettings};
+use super::libsodium::{KdfProfile, SecretString};
+use super::registry::IssuedPackage;
+use std::fs;
+use std::path::PathBuf;
+
+/// Owen's project, with the given recipient names and passphrases
+pub fn settings(recipients: &[(&str, &str)], threshold: u8) -> KinSettings {
+    super::libsodium::init().unwrap();
+
+    let recipients = recipients
+        .iter()
+        .map(|(name, passphrase)| KinRecipient {
+            name: name.to_string(),
+            passphrase: SecretString::copy_from(passphrase),
+        })
+        .collect();
+
+    KinSettings::new(
+        &String::from("Owen"),
+        recipients,
+        KdfProfile::Interactive,
+        threshold,
+    )
+}
+
+/// Owen's project, where each recipient's passphrase is their name
+pub fn settings_named(names: &[&str], threshold: u8) -> KinSettings {
+    let recipients: Vec<(&str, &str)> = names.iter().map(|name| (*name, *name)).collect();
+    settings(&recipients, threshold)
+}
+
+/// A package compiled with the first master key, with no content hash
+pub fn issued_package(id: &str, recipient: &str, peers: &[&str]) -> IssuedPackage {
+    IssuedPackage {
+        package_id: id.to_string(),
+        recipient: recipient.to_string(),
+        created: 0,
+        peers: peers.iter().map(|x| x.to_string()).collect(),
+        key_generation: 1,
+        content_hash: String::new(),
+        stale: None,
+    }
+}
+
+/// A new, empty directory that's deleted when it's dropped, even if the test
+/// using it fails first
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let mut id = [0; 8];
+        super::libsodium::init().unwrap();
+        super::libsodium::randombytes_into(&mut id);
+
+        let path = std::env::temp_dir().join(format!("kin-test-{}", super::libsodium::to_hex(&id)));
+        fs::create_dir(&path).unwrap();
+
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -1,3 +1,4 @@
+use super::libsodium::SecretString;
 use std::io;
 use std::io::Write;
 use std::iter::Iterator;
@@ -16,3 +17,9 @@ pub fn prompt(question: &str) -> Result<String, failure::Error> {
 
     Ok(String::from(input))
 }
+
+/// Prompts for a passphrase without echoing it to the terminal
+pub fn prompt_passphrase(question: &str) -> Result<SecretString, failure::Error> {
+    let passphrase = rpassword::read_password_from_tty(Some(question))?;
+    Ok(SecretString::from(passphrase))
+}
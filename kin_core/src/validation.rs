@@ -0,0 +1,98 @@
+use super::kinsettings::{KinRecipient, KinSettings};
+use super::{bail, Error};
+
+/// How many passphrases a backup needs before it can be unlocked. A
+/// threshold of 0 works the same as 1.
+pub fn passphrases_needed(threshold: u8) -> usize {
+    std::cmp::max(threshold, 1) as usize
+}
+
+/// How many different passphrases can unlock a backup with key slots for
+/// `peers`. Blank passphrases don't count, and peers sharing a passphrase
+/// only count once.
+pub fn usable_key_slots(peers: &[&KinRecipient]) -> usize {
+    let mut passphrases: Vec<&[u8]> = Vec::new();
+    for peer in peers.iter() {
+        let passphrase = peer.passphrase.as_bytes();
+        if !passphrase.is_empty() && !passphrases.contains(&passphrase) {
+            passphrases.push(passphrase);
+        }
+    }
+
+    passphrases.len()
+}
+
+/// Makes sure `recipient`'s backup could be unlocked by the other backup
+/// holders. Anything less and nobody could ever open it.
+pub fn check_package(settings: &KinSettings, recipient: &String) -> Result<(), Error> {
+    let peers = settings.get_peers(recipient)?;
+    let usable = usable_key_slots(&peers);
+    let needed = passphrases_needed(settings.threshold);
+
+    if usable < needed {
+        bail!(
+            "{}'s backup would have {} usable key slot(s), but needs {} passphrase(s) to unlock, so nobody could open it; add more recipients with `kin recipient add`",
+            recipient,
+            usable,
+            needed
+        );
+    }
+
+    Ok(())
+}
+
+/// Problems with a set of recipients that don't stop a project from being
+/// created, but do stop its backups from being compiled
+pub fn recipient_warnings(recipients: &[String], threshold: u8) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let needed = passphrases_needed(threshold);
+
+    if recipients.len() < 2 {
+        warnings.push(String::from(
+            "each backup is unlocked by the other backup holders, so you need at least two recipients before you can compile",
+        ));
+    } else if recipients.len() - 1 < needed {
+        warnings.push(format!(
+            "with a threshold of {}, you need at least {} recipients before you can compile",
+            needed,
+            needed + 1
+        ));
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::settings;
+
+    #[test]
+    fn packages_need_enough_key_slots() {
+        let alice = String::from("Alice");
+
+        assert!(check_package(&settings(&[("Alice", "a")], 1), &alice).is_err());
+        assert!(check_package(&settings(&[("Alice", "a"), ("Bob", "b")], 1), &alice).is_ok());
+        assert!(check_package(&settings(&[("Alice", "a"), ("Bob", "b")], 0), &alice).is_ok());
+        assert!(check_package(&settings(&[("Alice", "a"), ("Bob", "b")], 2), &alice).is_err());
+        assert!(check_package(&settings(&[("Alice", "a"), ("Bob", "")], 1), &alice).is_err());
+
+        let shared = settings(&[("Alice", "a"), ("Bob", "b"), ("Chuck", "b")], 2);
+        assert!(check_package(&shared, &alice).is_err());
+        assert!(check_package(&shared, &String::from("Bob")).is_ok());
+    }
+
+    #[test]
+    fn recipient_warnings() {
+        let names = |names: &[&str]| names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(super::recipient_warnings(&names(&[]), 1).len(), 1);
+        assert_eq!(super::recipient_warnings(&names(&["Alice"]), 1).len(), 1);
+        assert!(super::recipient_warnings(&names(&["Alice", "Bob"]), 1).is_empty());
+        assert_eq!(
+            super::recipient_warnings(&names(&["Alice", "Bob"]), 2),
+            vec!["with a threshold of 2, you need at least 3 recipients before you can compile"]
+        );
+    }
+}
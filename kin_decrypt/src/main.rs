@@ -14,25 +14,39 @@ fn main() -> kin_core::CliResult {
         None => None,
     };
 
-    let save_dialog_result = nfd::dialog_save()
-        .filter("zip")
-        .open()
-        .expect("save file dialog error");
+    if kin_core::decrypt::list_requested() {
+        let decrypt_args = DecryptArgs {
+            backup_dir: package_dir,
+            destination: None,
+            extract: false,
+            list: true,
+            only: Vec::new(),
+        };
+
+        return kin_core::decrypt::run(&decrypt_args);
+    }
+
+    // The decrypted files go straight into an empty folder, so nobody has to
+    // find and unzip an archive afterward
+    let folder_dialog_result = nfd::open_pick_folder(None).expect("folder dialog error");
 
-    let dest_path = match save_dialog_result {
+    let dest_path = match folder_dialog_result {
         Okay(path) => Some(PathBuf::from(path)),
-        OkayMultiple(_) => panic!("multiple files selected."),
+        OkayMultiple(_) => panic!("multiple folders selected."),
         Cancel => None,
     };
 
     if dest_path.is_none() {
-        println!("no destination file path selected.");
+        println!("no destination folder selected.");
         return Ok(());
     }
 
     let decrypt_args = DecryptArgs {
         backup_dir: package_dir,
         destination: dest_path,
+        extract: true,
+        list: false,
+        only: Vec::new(),
     };
 
     kin_core::decrypt::run(&decrypt_args)?;